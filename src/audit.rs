@@ -0,0 +1,260 @@
+use crate::error::{ErrorKind, ResultExt};
+use crate::paths;
+use crate::timestamp;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Kind of action recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditAction {
+    Protect,
+    Unprotect,
+    Clean,
+    Restore,
+    PurgeQuarantine,
+    Import,
+    Undo,
+}
+
+/// Result of an audited action
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOutcome {
+    /// The action changed something
+    Success,
+    /// The action was valid but there was nothing to change
+    Unchanged,
+    /// The action failed, see the error field
+    Failed,
+}
+
+/// A generation touched by an audited action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditedGeneration {
+    pub number: u32,
+    pub store_path: Option<PathBuf>,
+    /// Outcome for this generation alone, set when an action can partly fail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<AuditOutcome>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A single line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// RFC 3339 UTC timestamp
+    pub timestamp: String,
+    /// The real user, i.e. SUDO_USER when running under sudo
+    pub user: String,
+    /// Whether the action was run through sudo
+    pub sudo: bool,
+    pub action: AuditAction,
+    pub profile: String,
+    pub generations: Vec<AuditedGeneration>,
+    pub outcome: AuditOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    /// Create an entry for the current user and time
+    pub fn new(
+        action: AuditAction,
+        profile: &str,
+        generations: Vec<AuditedGeneration>,
+        outcome: AuditOutcome,
+    ) -> Self {
+        Self {
+            timestamp: timestamp::format_rfc3339(timestamp::now()),
+            user: paths::invoking_user_name(),
+            sudo: std::env::var_os("SUDO_USER").is_some(),
+            action,
+            profile: profile.to_string(),
+            generations,
+            outcome,
+            error: None,
+        }
+    }
+
+    /// Attach an error message, marking the entry as failed
+    pub fn failed(mut self, error: &anyhow::Error) -> Self {
+        self.outcome = AuditOutcome::Failed;
+        self.error = Some(format!("{:#}", error));
+        self
+    }
+
+    /// Check if the entry touched the given generation
+    pub fn involves(&self, generation: u32) -> bool {
+        self.generations.iter().any(|g| g.number == generation)
+    }
+}
+
+/// Append-only JSON-lines log of actions that change protections or generations
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Create an audit log writing to a specific path
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Open the audit log at its default location
+    /// /var/log/lock-generations/audit.log when running as root,
+    /// otherwise ~/.local/state/lock-generations/audit.log
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(paths::log_dir()?.join("audit.log")))
+    }
+
+    /// Path of the log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record an entry, warning instead of failing if the log cannot be written
+    /// The audited action has already happened at this point, so failing it would be misleading
+    pub fn record(&self, entry: &AuditEntry) {
+        if let Err(e) = self.append(entry) {
+            eprintln!("Warning: failed to write audit log: {:#}", e);
+        }
+    }
+
+    /// Append an entry to the log file
+    pub fn append(&self, entry: &AuditEntry) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create audit log directory: {}", parent.display())
+            })?;
+        }
+
+        let mut line = serde_json::to_string(entry).context("Failed to serialize audit entry")?;
+        line.push('\n');
+
+        // A single write of a whole line keeps concurrent appends from interleaving
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open audit log: {}", self.path.display()))?;
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("Failed to write audit log: {}", self.path.display()))?;
+
+        Ok(())
+    }
+
+    /// Read all entries from the log, oldest first
+    /// Returns an empty list if the log doesn't exist
+    pub fn entries(&self) -> Result<Vec<AuditEntry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read audit log: {}", self.path.display()))
+            .kind(ErrorKind::State)?;
+
+        let mut entries = Vec::new();
+        for (index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => eprintln!(
+                    "Warning: skipping malformed audit log line {}: {}",
+                    index + 1,
+                    e
+                ),
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn generation(number: u32) -> AuditedGeneration {
+        AuditedGeneration {
+            number,
+            store_path: Some(PathBuf::from(format!("/nix/store/{}-nixos-system", number))),
+            outcome: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let tmp_dir = TempDir::new().unwrap();
+        let log = AuditLog::new(tmp_dir.path().join("nested").join("audit.log"));
+
+        assert!(log.entries().unwrap().is_empty());
+
+        log.append(&AuditEntry::new(
+            AuditAction::Protect,
+            "/nix/var/nix/profiles/system",
+            vec![generation(42)],
+            AuditOutcome::Success,
+        ))
+        .unwrap();
+        log.append(
+            &AuditEntry::new(
+                AuditAction::Clean,
+                "/nix/var/nix/profiles/system",
+                vec![generation(79), generation(80)],
+                AuditOutcome::Success,
+            )
+            .failed(&anyhow::anyhow!("nix-env failed")),
+        )
+        .unwrap();
+
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, AuditAction::Protect);
+        assert!(entries[0].involves(42));
+        assert_eq!(entries[1].outcome, AuditOutcome::Failed);
+        assert_eq!(entries[1].error.as_deref(), Some("nix-env failed"));
+        assert!(entries[1].involves(80));
+        assert!(!entries[1].involves(42));
+    }
+
+    #[test]
+    fn test_malformed_lines_are_skipped() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("audit.log");
+        let log = AuditLog::new(path.clone());
+
+        log.append(&AuditEntry::new(
+            AuditAction::Unprotect,
+            "/nix/var/nix/profiles/system",
+            vec![generation(1)],
+            AuditOutcome::Unchanged,
+        ))
+        .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "not json").unwrap();
+
+        let entries = log.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outcome, AuditOutcome::Unchanged);
+    }
+
+    #[test]
+    fn test_unreadable_log_is_a_state_error() {
+        let tmp_dir = TempDir::new().unwrap();
+        let log = AuditLog::new(tmp_dir.path().to_path_buf());
+
+        let err = log.entries().unwrap_err();
+        assert_eq!(crate::error::kind_of(&err), Some(ErrorKind::State));
+    }
+}
@@ -1,20 +1,88 @@
 use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Profile managed when no other profile is configured
+pub const DEFAULT_PROFILE_PATH: &str = "/nix/var/nix/profiles/system";
 
 /// Represents a NixOS generation with its number and metadata
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Generation {
     pub number: u32,
+    /// Creation time in seconds since the Unix epoch, if known
+    pub created: Option<u64>,
+}
+
+/// All generations of a profile together with the current one, from a single listing
+/// Planning from one snapshot keeps a rebuild landing mid-run from mixing two listings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationSnapshot {
+    pub generations: Vec<Generation>,
+    pub current: u32,
+    /// The generation the running system was booted from, if it belongs to this profile
+    pub booted: Option<u32>,
+}
+
+/// A garbage collector root registered by this tool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcRoot {
+    pub name: String,
+    pub store_path: PathBuf,
 }
 
 /// Trait for abstracting NixOS command execution
 /// This allows for both real command execution and mocked behavior for testing
 pub trait NixOsCommandRunner {
+    /// List all generations and the current one from a single invocation or read
+    fn snapshot(&self) -> Result<GenerationSnapshot>;
+
     /// List all available NixOS system generations
-    fn list_generations(&self) -> Result<Vec<Generation>>;
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        Ok(self.snapshot()?.generations)
+    }
 
-    /// Get the current active generation number
-    fn get_current_generation(&self) -> Result<u32>;
+    /// Check whether a rebuild or garbage collection is running against the profile
+    /// Returns a description of what is running, None if the profile is idle
+    fn rebuild_in_progress(&self) -> Result<Option<String>>;
 
     /// Delete the specified generations using nix-env commands
     fn delete_generations(&self, generations: &[u32]) -> Result<()>;
+
+    /// The command delete_generations runs, shown by dry runs
+    fn delete_command(&self, generations: &[u32]) -> String {
+        let gen_list: Vec<String> = generations.iter().map(|g| g.to_string()).collect();
+        format!(
+            "nix-env --delete-generations {} -p {}",
+            gen_list.join(" "),
+            self.profile_path()
+        )
+    }
+
+    /// Get the profile path the generations belong to
+    fn profile_path(&self) -> &str;
+
+    /// Resolve the Nix store path a generation points to
+    fn generation_store_path(&self, generation: u32) -> Result<PathBuf>;
+
+    /// Register a store path as a GC root under the given name
+    fn add_gc_root(&self, name: &str, store_path: &Path) -> Result<()>;
+
+    /// Remove the GC root with the given name
+    /// Returns false if no such root existed
+    fn remove_gc_root(&self, name: &str) -> Result<bool>;
+
+    /// List all GC roots registered by this tool
+    fn list_gc_roots(&self) -> Result<Vec<GcRoot>>;
+
+    /// Create a dedicated profile (e.g. system-profiles/keep-nix-var-nix-profiles-system-42) pointing at a store path
+    fn pin_profile(&self, name: &str, store_path: &Path) -> Result<()>;
+
+    /// Remove a dedicated profile together with all of its generation links
+    /// Returns false if no such profile existed
+    fn unpin_profile(&self, name: &str) -> Result<bool>;
+
+    /// Check if a store path is still present, i.e. has not been garbage collected
+    fn store_path_exists(&self, store_path: &Path) -> Result<bool>;
+
+    /// Recreate a deleted generation's profile link pointing at its old store path
+    fn restore_generation(&self, generation: u32, store_path: &Path) -> Result<()>;
 }
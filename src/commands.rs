@@ -0,0 +1,2933 @@
+use crate::audit::{AuditAction, AuditEntry, AuditLog, AuditOutcome, AuditedGeneration};
+use crate::command_runner::{DEFAULT_PROFILE_PATH, GenerationSnapshot, NixOsCommandRunner};
+use crate::config::{Config, OutputFormat};
+use crate::error::{ErrorKind, ResultExt};
+use crate::export::{ExportFile, ExportedProtection};
+use crate::generation_parser::ParseMode;
+use crate::invariants;
+use crate::nix_command::NixCommand;
+#[cfg(feature = "nix-profile")]
+use crate::nix_profile_runner::{self, NixProfileRunner};
+use crate::protected_state::{ProtectedState, StateFile, StateMode};
+use crate::real_runner::RealNixOsRunner;
+use crate::recovery::{DeletedGeneration, RecoveryLog};
+use crate::retention::{Plan, RetentionPolicy};
+use crate::{paths, timestamp};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+/// Options controlling which generations `clean_generations` deletes and how
+#[derive(Debug)]
+pub struct CleanOptions {
+    /// Rules deciding which unprotected generations are kept
+    pub policy: RetentionPolicy,
+    /// Quarantine the deleted generations for this many days
+    pub quarantine: Option<u64>,
+    /// Only show what would be deleted
+    pub dry_run: bool,
+    /// Also honor the protections of every other user and of the shared state
+    pub all_users: bool,
+    /// Wait for a running rebuild to finish instead of refusing to clean
+    pub wait: bool,
+    /// How often a waiting clean checks whether the rebuild has finished
+    pub poll_interval: Duration,
+    /// Number of generations passed to a single delete call, at least 1
+    pub batch_size: usize,
+    /// Keep deleting the remaining batches after one fails
+    pub keep_going: bool,
+}
+
+impl Default for CleanOptions {
+    fn default() -> Self {
+        Self {
+            policy: RetentionPolicy::default(),
+            quarantine: None,
+            dry_run: false,
+            all_users: false,
+            wait: false,
+            poll_interval: Duration::from_secs(2),
+            // Well below the argument length limit
+            batch_size: 500,
+            keep_going: false,
+        }
+    }
+}
+
+/// What `clean_generations` deleted, and what it failed to delete
+#[derive(Debug, Default, PartialEq)]
+#[must_use = "failed deletions are only reported through `check`"]
+pub struct CleanReport {
+    /// Generations the retention policy picked for deletion
+    pub planned: Vec<u32>,
+    /// Generations that are gone now; empty for a dry run
+    pub deleted: Vec<u32>,
+    /// Generations still present after deleting them, with the error explaining why
+    pub failed: BTreeMap<u32, String>,
+}
+
+impl CleanReport {
+    /// Turn failed deletions into an error
+    pub fn check(&self) -> Result<()> {
+        if self.failed.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "Failed to delete {} of {} generation(s): {:?}",
+            self.failed.len(),
+            self.planned.len(),
+            self.failed.keys().collect::<Vec<_>>()
+        ))
+        .kind(ErrorKind::Runner)
+    }
+}
+
+/// Add protection to a specific generation to prevent it from being deleted
+///
+/// This function loads the current protection state, adds the specified generation
+/// to the protected list, and saves the updated state. If the generation is already
+/// protected, it informs the user without making changes.
+///
+/// With `gc_root`, the generation's store path is also registered as a Nix GC root,
+/// so its closure survives garbage collection started outside of this tool. With
+/// `pin_profile`, the generation is copied into a dedicated `keep-<gen>` system profile,
+/// which shows up as its own bootloader submenu and survives cleanups of the main profile.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to resolve the store path and register the pins
+/// * `audit` - The audit log the action is recorded in
+/// * `state_file` - The state file the protections are kept in
+/// * `generation` - The generation number to protect
+/// * `gc_root` - If true, also pins the generation with a GC root
+/// * `pin_profile` - If true, also pins the generation with a dedicated profile
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the state cannot be loaded or saved
+pub fn protect_generation(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    state_file: &StateFile,
+    generation: u32,
+    gc_root: bool,
+    pin_profile: bool,
+) -> Result<()> {
+    let saved = (|| -> Result<bool> {
+        let _lock = state_file.lock()?;
+        let mut state = state_file.load()?;
+
+        let changed = state.protect_as(generation, &paths::invoking_user_name());
+        if changed {
+            state_file.save(&state)?;
+            println!("Protected generation {}", generation);
+        } else {
+            println!("Generation {} is already protected", generation);
+        }
+        Ok(changed)
+    })();
+
+    let pinned = match saved {
+        Ok(_) => (|| -> Result<bool> {
+            if gc_root {
+                let store_path = runner.generation_store_path(generation)?;
+                runner.add_gc_root(&gc_root_name(runner, generation), &store_path)?;
+                println!("Pinned {} with a GC root", store_path.display());
+            }
+
+            if pin_profile {
+                let store_path = runner.generation_store_path(generation)?;
+                let name = keep_profile_name(runner, generation);
+                runner.pin_profile(&name, &store_path)?;
+                println!("Pinned generation {} in profile {}", generation, name);
+                println!("Run `nixos-rebuild boot` to add it to the bootloader menu");
+            }
+
+            Ok(gc_root || pin_profile)
+        })(),
+        Err(_) => Ok(false),
+    };
+
+    record_saved_action(
+        runner,
+        audit,
+        AuditAction::Protect,
+        &[generation],
+        &saved,
+        &pinned,
+    );
+    saved.and(pinned).map(|_| ())
+}
+
+/// Remove protection from a specific generation, allowing it to be deleted
+///
+/// This function loads the current protection state, removes the specified generation
+/// from the protected list, and saves the updated state. If the generation was not
+/// protected, it informs the user without making changes. Any GC root or dedicated
+/// `keep-<gen>` profile registered for the generation is removed as well.
+///
+/// Generations protected by the read-only system layer are refused.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to remove the GC root and dedicated profile
+/// * `audit` - The audit log the action is recorded in
+/// * `state_file` - The state file the protections are kept in
+/// * `generation` - The generation number to unprotect
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the state cannot be loaded or saved
+pub fn unprotect_generation(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    state_file: &StateFile,
+    generation: u32,
+) -> Result<()> {
+    let saved = (|| -> Result<bool> {
+        let _lock = state_file.lock()?;
+        let mut state = state_file.load()?;
+
+        if let Some(source) = state.system_source(generation) {
+            anyhow::bail!(
+                "Generation {} is protected by the system layer ({}) and cannot be unprotected here",
+                generation,
+                source.display()
+            );
+        }
+
+        let changed = state.unprotect(generation);
+        if changed {
+            state_file.save(&state)?;
+            println!("Unprotected generation {}", generation);
+        } else {
+            println!("Generation {} was not protected", generation);
+        }
+        Ok(changed)
+    })();
+
+    let unpinned = match saved {
+        Ok(_) => (|| -> Result<bool> {
+            let mut changed = false;
+            if runner.remove_gc_root(&gc_root_name(runner, generation))? {
+                println!("Removed GC root for generation {}", generation);
+                changed = true;
+            }
+
+            let name = keep_profile_name(runner, generation);
+            if runner.unpin_profile(&name)? {
+                println!("Removed profile {}", name);
+                changed = true;
+            }
+            Ok(changed)
+        })(),
+        Err(_) => Ok(false),
+    };
+
+    record_saved_action(
+        runner,
+        audit,
+        AuditAction::Unprotect,
+        &[generation],
+        &saved,
+        &unpinned,
+    );
+    saved.and(unpinned).map(|_| ())
+}
+
+/// Clean up old NixOS generations while preserving protected and recent ones
+///
+/// This function determines which generations should be deleted based on the following rules:
+/// - The current active generation is always preserved
+/// - All explicitly protected generations are preserved, including those of every
+///   user and the shared state when `all_users` is set
+/// - Generations kept by a rule of the retention policy are preserved, see `RetentionPolicy`
+/// - All other generations are deleted
+///
+/// In quarantine mode, each deleted generation's store path is first registered as a
+/// temporary GC root. The generations disappear from the profile, but their closures
+/// survive garbage collection until `purge-quarantine` releases them after the grace period.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner to use for querying and deleting generations
+/// * `audit` - The audit log the deletion is recorded in (dry runs are not recorded)
+/// * `recovery` - The log the deleted generations are recorded in so they can be restored
+/// * `state_file` - The state file the protections of the invoking user are kept in
+/// * `options` - Retention and deletion options, see `CleanOptions`
+///
+/// # Returns
+///
+/// Returns what was deleted and what failed to delete; failed deletions are not an error
+/// until `CleanReport::check`. Returns an error if the clean could not start, or if the
+/// profile could not be verified afterwards.
+pub fn clean_generations(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    recovery: &RecoveryLog,
+    state_file: &StateFile,
+    options: &CleanOptions,
+) -> Result<CleanReport> {
+    let CleanOptions {
+        ref policy,
+        quarantine,
+        dry_run,
+        all_users,
+        wait,
+        poll_interval,
+        batch_size,
+        keep_going,
+    } = *options;
+
+    if batch_size == 0 {
+        return Err(anyhow::anyhow!("The batch size must be at least 1")).kind(ErrorKind::Policy);
+    }
+
+    // Deleting while a rebuild adds a generation races with it
+    if !dry_run {
+        wait_for_rebuild(runner, wait, poll_interval)?;
+    } else if let Some(reason) = runner.rebuild_in_progress()? {
+        println!("Note: {}, clean would refuse to run now", reason);
+    }
+
+    // Hold the lock until the end, so protections cannot change mid-clean
+    let _lock = state_file.lock_shared()?;
+    let state = load_protections(state_file, all_users)?;
+    let snapshot = runner.snapshot()?;
+    let to_delete = plan_clean(&snapshot, &state, policy).delete;
+
+    if to_delete.is_empty() {
+        println!("No generations to delete");
+        if !dry_run {
+            record_action(runner, audit, AuditAction::Clean, &[], &Ok(false));
+        }
+        return Ok(CleanReport::default());
+    }
+
+    if dry_run {
+        println!(
+            "[DRY RUN] Would delete {} generation(s): {:?}",
+            to_delete.len(),
+            to_delete
+        );
+        println!();
+        println!("Command that would be executed:");
+        println!("  {}", runner.delete_command(&to_delete));
+        if let Some(days) = quarantine {
+            println!();
+            println!(
+                "Their closures would be quarantined with GC roots for {} day(s)",
+                days
+            );
+        }
+        Ok(CleanReport {
+            planned: to_delete,
+            ..Default::default()
+        })
+    } else {
+        // A rebuild may have landed since the plan was made, never delete from a stale plan
+        if let Err(e) = verify_snapshot(runner, &snapshot) {
+            let result = Err(e.context(format!(
+                "Generations of {} changed while planning the clean, nothing was deleted. \
+                 Run clean again.",
+                runner.profile_path()
+            )))
+            .kind(ErrorKind::Busy);
+            record_action(runner, audit, AuditAction::Clean, &to_delete, &result);
+            return result.map(|_| CleanReport::default());
+        }
+
+        println!(
+            "Deleting {} generation(s): {:?}",
+            to_delete.len(),
+            to_delete
+        );
+        // Resolve store paths up front, the profile links are gone after deletion
+        let generations = audited_generations(runner, &to_delete);
+
+        // Remember what is about to go, so an accidental clean can be undone until the next GC
+        let deleted_at = timestamp::now();
+        let quarantined_until = quarantine.map(|days| deleted_at + days * 86_400);
+        let doomed: Vec<DeletedGeneration> = generations
+            .iter()
+            .filter_map(|g| {
+                Some(DeletedGeneration {
+                    profile: runner.profile_path().to_string(),
+                    generation: g.number,
+                    store_path: g.store_path.clone()?,
+                    deleted_at,
+                    quarantined_until,
+                })
+            })
+            .collect();
+        if let Err(e) = recovery.record(&doomed) {
+            let result = Err(e);
+            record_action(runner, audit, AuditAction::Clean, &to_delete, &result);
+            return result.map(|_| CleanReport::default());
+        }
+
+        // Pin the closures before the profile links go away
+        if let Some(until) = quarantined_until {
+            let mut pinned = Vec::new();
+            let mut failure = None;
+            for deleted in &doomed {
+                let root = quarantine_root_name(runner, deleted.generation);
+                match runner.add_gc_root(&root, &deleted.store_path) {
+                    Ok(()) => pinned.push(root),
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                }
+            }
+            if let Some(e) = failure {
+                // Nothing was deleted yet, so nothing may be left pointing at the generations
+                for root in &pinned {
+                    if let Err(e) = runner.remove_gc_root(root) {
+                        eprintln!("Warning: {:#}", e);
+                    }
+                }
+                if let Err(e) = recovery.forget(&doomed) {
+                    eprintln!("Warning: {:#}", e);
+                }
+                let result =
+                    Err(e.context("Failed to quarantine the generations, nothing was deleted"));
+                record_action(runner, audit, AuditAction::Clean, &to_delete, &result);
+                return result.map(|_| CleanReport::default());
+            }
+            println!(
+                "Quarantined {} generation(s) until {}",
+                doomed.len(),
+                timestamp::format_rfc3339(until)
+            );
+        }
+
+        let batch_errors = delete_in_batches(runner, &to_delete, batch_size, keep_going);
+
+        // What went away counts, not what nix-env reported
+        let after = runner.snapshot();
+        let errors: BTreeMap<u32, String> = match &after {
+            Ok(after) => to_delete
+                .iter()
+                .filter(|&&g| after.generations.iter().any(|l| l.number == g))
+                .map(|&g| {
+                    let error = batch_errors.get(&g).cloned().unwrap_or_else(|| {
+                        "still present although deleting it succeeded".to_string()
+                    });
+                    (g, error)
+                })
+                .collect(),
+            Err(_) => batch_errors.clone(),
+        };
+
+        // Generations still present were not deleted, so there is nothing to restore or quarantine
+        if after.is_ok() {
+            let survived: Vec<DeletedGeneration> = doomed
+                .iter()
+                .filter(|d| errors.contains_key(&d.generation))
+                .cloned()
+                .collect();
+            if !survived.is_empty()
+                && let Err(e) = recovery.forget(&survived)
+            {
+                eprintln!("Warning: {:#}", e);
+            }
+            if quarantined_until.is_some() {
+                for deleted in &survived {
+                    let root = quarantine_root_name(runner, deleted.generation);
+                    if let Err(e) = runner.remove_gc_root(&root) {
+                        eprintln!("Warning: {:#}", e);
+                    }
+                }
+            }
+        }
+
+        let mut generations = generations;
+        for generation in &mut generations {
+            let error = errors.get(&generation.number).cloned();
+            generation.outcome = Some(if error.is_some() {
+                AuditOutcome::Failed
+            } else {
+                AuditOutcome::Success
+            });
+            generation.error = error;
+        }
+
+        let mut entry = AuditEntry::new(
+            AuditAction::Clean,
+            runner.profile_path(),
+            generations,
+            AuditOutcome::Success,
+        );
+        let verified = match after {
+            Err(e) => Err(e.context(
+                "Failed to list the generations after deleting, the clean could not be verified",
+            )),
+            Ok(after) => invariants::check_clean(
+                runner.profile_path(),
+                &snapshot,
+                &after,
+                &state.all_protected(),
+                &to_delete,
+                &batch_errors,
+            )
+            .map_err(anyhow::Error::new),
+        };
+        let report = CleanReport {
+            deleted: to_delete
+                .iter()
+                .copied()
+                .filter(|g| !errors.contains_key(g))
+                .collect(),
+            planned: to_delete,
+            failed: errors,
+        };
+        match &verified {
+            Err(e) => entry = entry.failed(e),
+            Ok(()) => {
+                if let Err(e) = report.check() {
+                    entry = entry.failed(&e);
+                }
+            }
+        }
+        audit.record(&entry);
+        verified?;
+        Ok(report)
+    }
+}
+
+/// List all currently protected generations
+///
+/// This function loads the protection state and displays all generations that are
+/// currently marked as protected, together with the layer protecting them. The list
+/// is sorted in ascending order by generation number for easy reading.
+///
+/// # Arguments
+///
+/// * `state_file` - The state file the protections are kept in
+/// * `output` - How the list is printed
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the state cannot be loaded
+pub fn list_protected(state_file: &StateFile, output: OutputFormat) -> Result<()> {
+    let state = state_file.load()?;
+    let layer = match state_file.mode() {
+        StateMode::User => "user",
+        StateMode::Shared => "shared",
+    };
+
+    let protected: Vec<ProtectedEntry> = state
+        .all_protected()
+        .into_iter()
+        .map(|gen_num| ProtectedEntry {
+            generation: gen_num,
+            layer: state
+                .protected_generations
+                .contains(&gen_num)
+                .then_some(layer),
+            protected_by: state.protected_by(gen_num),
+            system_source: state.system_source(gen_num),
+        })
+        .collect();
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&protected)?);
+    } else if protected.is_empty() {
+        println!("No protected generations");
+    } else {
+        println!("Protected generations:");
+        for entry in &protected {
+            let mut layers = Vec::new();
+            match (entry.layer, entry.protected_by) {
+                (Some(layer), Some(user)) => layers.push(format!("{}, by {}", layer, user)),
+                (Some(layer), None) => layers.push(layer.to_string()),
+                (None, _) => {}
+            }
+            if let Some(source) = entry.system_source {
+                layers.push(format!("system: {}", source.display()));
+            }
+            println!("  {}  ({})", entry.generation, layers.join(", "));
+        }
+    }
+
+    Ok(())
+}
+
+/// A protected generation as printed by `list`
+#[derive(serde::Serialize)]
+struct ProtectedEntry<'a> {
+    generation: u32,
+    /// "user" or "shared", if protected in the state file of the active mode
+    layer: Option<&'a str>,
+    protected_by: Option<&'a str>,
+    /// The system layer file protecting the generation, if any
+    system_source: Option<&'a Path>,
+}
+
+/// Load the protections `clean` honors
+/// With `all_users`, the protections of every user and of the shared state are merged in
+pub fn load_protections(state_file: &StateFile, all_users: bool) -> Result<ProtectedState> {
+    if all_users {
+        state_file.load_all_users()
+    } else {
+        state_file.load()
+    }
+}
+
+/// Apply a retention policy to a snapshot of the generations
+pub fn plan_clean(
+    snapshot: &GenerationSnapshot,
+    state: &ProtectedState,
+    policy: &RetentionPolicy,
+) -> Plan {
+    policy.plan(
+        &snapshot.generations,
+        snapshot.current,
+        snapshot.booted,
+        &state.all_protected(),
+        timestamp::now(),
+    )
+}
+
+/// Delete generations in batches of `batch_size`, staying below the argument length limit
+///
+/// Stops at the first failing batch unless `keep_going` is set. Returns the
+/// generations of the failed and skipped batches, with the error explaining why.
+/// A failing nix-env may still have deleted some of them, so the caller has to
+/// list the generations again to find out what actually went away.
+fn delete_in_batches(
+    runner: &dyn NixOsCommandRunner,
+    generations: &[u32],
+    batch_size: usize,
+    keep_going: bool,
+) -> BTreeMap<u32, String> {
+    let mut errors = BTreeMap::new();
+    let mut failed = false;
+    for batch in generations.chunks(batch_size) {
+        if failed && !keep_going {
+            for &generation in batch {
+                errors.insert(
+                    generation,
+                    "not attempted after an earlier failure".to_string(),
+                );
+            }
+            continue;
+        }
+        if let Err(e) = runner.delete_generations(batch) {
+            failed = true;
+            for &generation in batch {
+                errors.insert(generation, format!("{:#}", e));
+            }
+        }
+    }
+
+    errors
+}
+
+/// Make sure no rebuild or garbage collection is running against the profile
+/// Polls every `poll_interval` until it is idle when `wait` is set, bails out otherwise
+fn wait_for_rebuild(
+    runner: &dyn NixOsCommandRunner,
+    wait: bool,
+    poll_interval: Duration,
+) -> Result<()> {
+    let mut waiting = false;
+    while let Some(reason) = runner.rebuild_in_progress()? {
+        if !wait {
+            return Err(anyhow::anyhow!(
+                "Refusing to clean {}: {}. Run clean again once it is done, or pass --wait.",
+                runner.profile_path(),
+                reason
+            ))
+            .kind(ErrorKind::Busy);
+        }
+        if !waiting {
+            println!("Waiting for the rebuild to finish: {}", reason);
+            waiting = true;
+        }
+        std::thread::sleep(poll_interval);
+    }
+    Ok(())
+}
+
+/// Check that the generations still match a snapshot taken earlier
+fn verify_snapshot(runner: &dyn NixOsCommandRunner, snapshot: &GenerationSnapshot) -> Result<()> {
+    let fresh = runner.snapshot()?;
+    if fresh != *snapshot {
+        let numbers =
+            |s: &GenerationSnapshot| s.generations.iter().map(|g| g.number).collect::<Vec<_>>();
+        anyhow::bail!(
+            "Generations changed from {:?} (current {}) to {:?} (current {})",
+            numbers(snapshot),
+            snapshot.current,
+            numbers(&fresh),
+            fresh.current
+        );
+    }
+    Ok(())
+}
+
+/// Show which generations `clean` would keep or delete, and why
+///
+/// Each kept generation is listed with the first reason that keeps it: being the current
+/// generation, being protected, or a rule of the retention policy. Rules coming from a
+/// preset are named together with the preset.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to query the generations
+/// * `state_file` - The state file the protections of the invoking user are kept in
+/// * `policy` - The retention policy `clean` would apply
+/// * `all_users` - If true, the protections of every user are honored, as `clean` does as root
+/// * `output` - How the result is printed
+///
+/// # Returns
+///
+/// Returns the printed generations, or an error if the generations or protections cannot be
+/// loaded
+pub fn explain_generations(
+    runner: &dyn NixOsCommandRunner,
+    state_file: &StateFile,
+    policy: &RetentionPolicy,
+    all_users: bool,
+    output: OutputFormat,
+) -> Result<Vec<ExplainedGeneration>> {
+    let state = load_protections(state_file, all_users)?;
+    let snapshot = runner.snapshot()?;
+    let plan = plan_clean(&snapshot, &state, policy);
+
+    let explained: Vec<ExplainedGeneration> = snapshot
+        .generations
+        .into_iter()
+        .map(|g| {
+            let reason = plan
+                .keep
+                .get(&g.number)
+                .map(|reason| match policy.preset_of(reason) {
+                    Some(name) => format!("{} (policy {})", reason, name),
+                    None => reason.to_string(),
+                });
+            ExplainedGeneration {
+                generation: g.number,
+                created: g.created.map(timestamp::format_rfc3339),
+                keep: reason.is_some(),
+                reason,
+            }
+        })
+        .collect();
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&explained)?);
+        return Ok(explained);
+    }
+
+    if let Some(name) = &policy.name {
+        println!("Policy: {}", name);
+    }
+    for entry in &explained {
+        let created = entry.created.as_deref().unwrap_or("unknown");
+        match &entry.reason {
+            Some(reason) => println!("  {}  {}  keep    {}", entry.generation, created, reason),
+            None => println!("  {}  {}  delete", entry.generation, created),
+        }
+    }
+    Ok(explained)
+}
+
+/// A generation as printed by `explain`
+#[derive(Debug, serde::Serialize)]
+pub struct ExplainedGeneration {
+    pub generation: u32,
+    /// Creation time as RFC 3339, if known
+    pub created: Option<String>,
+    pub keep: bool,
+    /// Why the generation is kept, None if clean would delete it
+    pub reason: Option<String>,
+}
+
+/// Restore a generation deleted by a previous clean
+///
+/// The generation's profile link is recreated pointing at the store path recorded
+/// right before it was deleted. This only works as long as that store path has not
+/// been garbage collected.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to check the store path and recreate the link
+/// * `audit` - The audit log the action is recorded in
+/// * `recovery` - The log of deleted generations
+/// * `generation` - The generation number to restore
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the generation cannot be restored
+pub fn restore_generation(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    recovery: &RecoveryLog,
+    generation: u32,
+) -> Result<()> {
+    let profile = runner.profile_path();
+    let Some(deleted) = recovery.find(profile, generation)? else {
+        anyhow::bail!("No record of generation {} being deleted", generation);
+    };
+
+    if runner
+        .list_generations()?
+        .iter()
+        .any(|g| g.number == generation)
+    {
+        anyhow::bail!("Generation {} still exists, nothing to restore", generation);
+    }
+
+    let result = (|| -> Result<()> {
+        if !runner.store_path_exists(&deleted.store_path)? {
+            anyhow::bail!(
+                "Generation {} cannot be restored: its store path {} has been garbage collected",
+                generation,
+                deleted.store_path.display()
+            );
+        }
+        runner.restore_generation(generation, &deleted.store_path)
+    })();
+
+    let mut entry = AuditEntry::new(
+        AuditAction::Restore,
+        profile,
+        vec![AuditedGeneration {
+            number: generation,
+            store_path: Some(deleted.store_path.clone()),
+            outcome: None,
+            error: None,
+        }],
+        AuditOutcome::Success,
+    );
+    if let Err(e) = &result {
+        entry = entry.failed(e);
+    }
+    audit.record(&entry);
+    result?;
+
+    // The profile link keeps the closure alive again, a quarantine root is no longer needed
+    runner.remove_gc_root(&quarantine_root_name(runner, generation))?;
+
+    recovery.remove(profile, generation)?;
+    println!(
+        "Restored generation {} ({})",
+        generation,
+        deleted.store_path.display()
+    );
+    println!("Run `nixos-rebuild boot` to add it back to the bootloader menu");
+
+    Ok(())
+}
+
+/// List the deleted generations of the runner's profile and whether they can be restored
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to check the store paths
+/// * `recovery` - The log of deleted generations
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the log cannot be read
+pub fn list_restorable(runner: &dyn NixOsCommandRunner, recovery: &RecoveryLog) -> Result<()> {
+    let entries: Vec<DeletedGeneration> = recovery
+        .entries()?
+        .into_iter()
+        .filter(|e| e.profile == runner.profile_path())
+        .collect();
+
+    if entries.is_empty() {
+        println!("No deleted generations recorded");
+        return Ok(());
+    }
+
+    println!("Deleted generations:");
+    for entry in entries {
+        let status = if !runner.store_path_exists(&entry.store_path)? {
+            "garbage collected".to_string()
+        } else if let Some(until) = entry.quarantined_until {
+            format!("quarantined until {}", timestamp::format_rfc3339(until))
+        } else {
+            "restorable".to_string()
+        };
+        println!(
+            "  {}  deleted {}  {}  ({})",
+            entry.generation,
+            timestamp::format_rfc3339(entry.deleted_at),
+            entry.store_path.display(),
+            status
+        );
+    }
+
+    Ok(())
+}
+
+/// Release quarantined generations whose grace period has passed
+///
+/// The quarantine GC roots of expired generations are removed and their records dropped
+/// from the recovery log. Nothing is deleted from the store right away: the next garbage
+/// collection, e.g. `nix-collect-garbage`, deletes their closures.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to remove the GC roots
+/// * `audit` - The audit log the action is recorded in
+/// * `recovery` - The log of deleted generations
+/// * `all` - If true, releases all quarantined generations regardless of their grace period
+/// * `dry_run` - If true, only reports what would be released
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the log or the roots cannot be accessed
+pub fn purge_quarantine(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    recovery: &RecoveryLog,
+    all: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let now = timestamp::now();
+    let mut expired: Vec<u32> = recovery
+        .entries()?
+        .into_iter()
+        .filter(|e| e.profile == runner.profile_path())
+        .filter(|e| e.quarantined_until.is_some_and(|until| all || until <= now))
+        .map(|e| e.generation)
+        .collect();
+    expired.sort_unstable();
+    expired.dedup();
+
+    if expired.is_empty() {
+        println!("No quarantined generations to purge");
+        return Ok(());
+    }
+
+    if dry_run {
+        println!(
+            "[DRY RUN] Would release {} quarantined generation(s): {:?}",
+            expired.len(),
+            expired
+        );
+        return Ok(());
+    }
+
+    let result = (|| -> Result<bool> {
+        for &generation in &expired {
+            runner.remove_gc_root(&quarantine_root_name(runner, generation))?;
+            recovery.remove(runner.profile_path(), generation)?;
+        }
+        Ok(true)
+    })();
+    record_action(
+        runner,
+        audit,
+        AuditAction::PurgeQuarantine,
+        &expired,
+        &result,
+    );
+    result?;
+
+    println!(
+        "Released {} quarantined generation(s): {:?}",
+        expired.len(),
+        expired
+    );
+    println!("Run `nix-collect-garbage` to reclaim their disk space");
+
+    Ok(())
+}
+
+/// Show the audit log, optionally filtered by generation and action
+///
+/// # Arguments
+///
+/// * `audit` - The audit log to read
+/// * `generation` - If set, only entries touching this generation are shown
+/// * `action` - If set, only entries of this action are shown
+/// * `limit` - If set, only the N most recent matching entries are shown
+/// * `output` - How the entries are printed
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the log cannot be read
+pub fn show_history(
+    audit: &AuditLog,
+    generation: Option<u32>,
+    action: Option<AuditAction>,
+    limit: Option<usize>,
+    output: OutputFormat,
+) -> Result<()> {
+    let entries: Vec<AuditEntry> = audit
+        .entries()?
+        .into_iter()
+        .filter(|e| generation.is_none_or(|g| e.involves(g)))
+        .filter(|e| action.is_none_or(|a| e.action == a))
+        .collect();
+    let skip = limit.map_or(0, |n| entries.len().saturating_sub(n));
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&entries[skip..])?);
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        println!("No matching history in {}", audit.path().display());
+        return Ok(());
+    }
+
+    for entry in &entries[skip..] {
+        let action = match entry.action {
+            AuditAction::Protect => "protect",
+            AuditAction::Unprotect => "unprotect",
+            AuditAction::Clean => "clean",
+            AuditAction::Restore => "restore",
+            AuditAction::PurgeQuarantine => "purge-quarantine",
+            AuditAction::Import => "import",
+            AuditAction::Undo => "undo",
+        };
+        let outcome = match (&entry.outcome, &entry.error) {
+            (AuditOutcome::Failed, Some(error)) => format!("failed: {}", error),
+            (AuditOutcome::Failed, None) => "failed".to_string(),
+            (AuditOutcome::Unchanged, _) => "unchanged".to_string(),
+            (AuditOutcome::Success, _) => "success".to_string(),
+        };
+        let generations: Vec<String> = entry
+            .generations
+            .iter()
+            .map(|g| match g.outcome {
+                // Only partly failed actions record outcomes per generation
+                Some(AuditOutcome::Failed) => format!("{} (not deleted)", g.number),
+                _ => g.number.to_string(),
+            })
+            .collect();
+        let user = if entry.sudo {
+            format!("{} (sudo)", entry.user)
+        } else {
+            entry.user.clone()
+        };
+
+        println!(
+            "{}  {}  {} [{}]  {}  {}",
+            entry.timestamp,
+            user,
+            action,
+            generations.join(", "),
+            entry.profile,
+            outcome
+        );
+    }
+
+    Ok(())
+}
+
+/// Export the protections of the state file to a portable file
+///
+/// Each protected generation is written with the store path it points to and the user
+/// who protected it. Protections of the read-only system layer are not exported.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to resolve the store paths
+/// * `state_file` - The state file the protections are kept in
+/// * `file` - The file to write, or None to print to stdout
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the state cannot be loaded or the file written
+pub fn export_protections(
+    runner: &dyn NixOsCommandRunner,
+    state_file: &StateFile,
+    file: Option<&Path>,
+) -> Result<()> {
+    let state = state_file.load()?;
+    let mut generations: Vec<u32> = state.protected_generations.iter().copied().collect();
+    generations.sort_unstable();
+
+    let protections = generations
+        .into_iter()
+        .map(|generation| ExportedProtection {
+            generation,
+            store_path: runner.generation_store_path(generation).ok(),
+            protected_by: state.protected_by(generation).map(str::to_string),
+        })
+        .collect();
+    let export = ExportFile::new(runner.profile_path(), protections);
+
+    match file {
+        Some(path) => {
+            export.write(path)?;
+            println!(
+                "Exported {} protection(s) to {}",
+                export.protections.len(),
+                path.display()
+            );
+        }
+        None => println!("{}", export.to_json()?),
+    }
+    Ok(())
+}
+
+/// Options controlling how `import_protections` applies an export file
+#[derive(Debug, Default)]
+pub struct ImportOptions {
+    /// Drop current protections that are not in the export
+    pub replace: bool,
+    /// Import generations whose store path does not match as well
+    pub force: bool,
+    /// Only show what would be imported
+    pub dry_run: bool,
+}
+
+/// Import protections from a file written by `export`
+///
+/// Every exported generation is checked against the local profile first: generations
+/// that do not exist locally, or point to another store path than in the export, are
+/// reported and skipped unless `force` is set. The remaining ones are merged into the
+/// current protections, or replace them with `replace`.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to check the store paths
+/// * `audit` - The audit log the action is recorded in (dry runs are not recorded)
+/// * `state_file` - The state file the protections are kept in
+/// * `file` - The export file to read
+/// * `options` - How the export is applied, see `ImportOptions`
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the file or state cannot be read or saved
+pub fn import_protections(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    state_file: &StateFile,
+    file: &Path,
+    options: &ImportOptions,
+) -> Result<()> {
+    let export = ExportFile::read(file)?;
+    if export.profile != runner.profile_path() {
+        println!(
+            "Note: exported from profile {}, importing into {}",
+            export.profile,
+            runner.profile_path()
+        );
+    }
+
+    let mut accepted = Vec::new();
+    for protection in &export.protections {
+        let generation = protection.generation;
+        let problem = match (
+            &protection.store_path,
+            runner.generation_store_path(generation),
+        ) {
+            (_, Err(_)) => Some("does not exist in the local profile".to_string()),
+            (Some(exported), Ok(local)) if *exported != local => Some(format!(
+                "points to {} locally, but to {} in the export",
+                local.display(),
+                exported.display()
+            )),
+            _ => None,
+        };
+
+        if let Some(problem) = problem {
+            if options.force {
+                println!("Generation {} {}, importing anyway", generation, problem);
+            } else {
+                println!("Generation {} {}, skipped", generation, problem);
+                continue;
+            }
+        }
+        accepted.push(protection);
+    }
+    let imported: Vec<u32> = accepted.iter().map(|p| p.generation).collect();
+
+    let result = (|| -> Result<bool> {
+        let _lock = state_file.lock()?;
+        let mut state = state_file.load()?;
+
+        let mut removed = Vec::new();
+        if options.replace {
+            let mut current: Vec<u32> = state.protected_generations.iter().copied().collect();
+            current.sort_unstable();
+            for generation in current {
+                if !imported.contains(&generation) {
+                    state.unprotect(generation);
+                    removed.push(generation);
+                }
+            }
+        }
+
+        let invoking_user = paths::invoking_user_name();
+        let mut added = Vec::new();
+        for protection in &accepted {
+            let user = protection.protected_by.as_deref().unwrap_or(&invoking_user);
+            if state.protect_as(protection.generation, user) {
+                added.push(protection.generation);
+            }
+        }
+
+        let (protect, unprotect) = if options.dry_run {
+            ("[DRY RUN] Would protect", "[DRY RUN] Would unprotect")
+        } else {
+            ("Protected", "Unprotected")
+        };
+        if !added.is_empty() {
+            println!("{} generation(s): {:?}", protect, added);
+        }
+        if !removed.is_empty() {
+            println!("{} generation(s): {:?}", unprotect, removed);
+        }
+
+        let changed = !added.is_empty() || !removed.is_empty();
+        if !changed {
+            println!("Nothing to import");
+        } else if !options.dry_run {
+            state_file.save(&state)?;
+        }
+        Ok(changed)
+    })();
+
+    if !options.dry_run {
+        record_action(runner, audit, AuditAction::Import, &imported, &result);
+    }
+    result.map(|_| ())
+}
+
+/// Revert the most recent change to the protections
+///
+/// Every save of the state file keeps a snapshot of the previous state. This puts back
+/// the most recent snapshot, e.g. to take back a mistaken unprotect before running clean.
+/// GC roots and keep profiles are not touched; `sync-roots` brings the roots in line.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner, used for the audit log
+/// * `audit` - The audit log the action is recorded in
+/// * `state_file` - The state file the protections are kept in
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the state or its history cannot be read or saved
+pub fn undo_change(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    state_file: &StateFile,
+) -> Result<()> {
+    let mut changed = Vec::new();
+    let result = (|| -> Result<bool> {
+        let _lock = state_file.lock()?;
+        let before = state_file.load()?;
+        let Some(snapshot) = state_file.undo()? else {
+            println!("Nothing to undo");
+            return Ok(false);
+        };
+        let after = state_file.load()?;
+
+        println!(
+            "Reverted the change saved by {} at {}",
+            snapshot.user,
+            timestamp::format_rfc3339(snapshot.saved_at)
+        );
+        let (protected, unprotected) =
+            protection_changes(&before.protected_generations, &after.protected_generations);
+        if !protected.is_empty() {
+            println!("Protected generation(s): {:?}", protected);
+        }
+        if !unprotected.is_empty() {
+            println!("Unprotected generation(s): {:?}", unprotected);
+        }
+        println!("Run `sync-roots` to update the GC roots");
+
+        changed = [protected, unprotected].concat();
+        Ok(true)
+    })();
+
+    record_action(runner, audit, AuditAction::Undo, &changed, &result);
+    result.map(|_| ())
+}
+
+/// Show the recent changes to the protections, newest first
+///
+/// Each change is derived by comparing a snapshot with the state that replaced it.
+///
+/// # Arguments
+///
+/// * `state_file` - The state file the protections are kept in
+/// * `limit` - If set, only the N most recent changes are shown
+/// * `output` - How the changes are printed
+///
+/// # Returns
+///
+/// Returns the printed changes, or an error if the state or its history cannot be read
+pub fn show_state_history(
+    state_file: &StateFile,
+    limit: Option<usize>,
+    output: OutputFormat,
+) -> Result<Vec<StateChange>> {
+    let mut after = state_file.load()?.protected_generations;
+    let mut changes = Vec::new();
+    for snapshot in state_file.history()?.into_iter().rev() {
+        let before = match snapshot.state {
+            Some(value) => ProtectedState::from_value(value)?.protected_generations,
+            None => HashSet::new(),
+        };
+        let (protected, unprotected) = protection_changes(&before, &after);
+        changes.push(StateChange {
+            saved_at: timestamp::format_rfc3339(snapshot.saved_at),
+            user: snapshot.user,
+            protected,
+            unprotected,
+        });
+        after = before;
+    }
+    changes.truncate(limit.unwrap_or(changes.len()));
+
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&changes)?);
+        return Ok(changes);
+    }
+
+    if changes.is_empty() {
+        println!("No state history for {}", state_file.path().display());
+        return Ok(changes);
+    }
+    for (i, change) in changes.iter().enumerate() {
+        let mut parts = Vec::new();
+        if !change.protected.is_empty() {
+            parts.push(format!("protected {:?}", change.protected));
+        }
+        if !change.unprotected.is_empty() {
+            parts.push(format!("unprotected {:?}", change.unprotected));
+        }
+        if parts.is_empty() {
+            parts.push("no change".to_string());
+        }
+        let next = if i == 0 { "  <- undo reverts this" } else { "" };
+        println!(
+            "{}  {}  {}{}",
+            change.saved_at,
+            change.user,
+            parts.join(", "),
+            next
+        );
+    }
+    Ok(changes)
+}
+
+/// A change to the protections as printed by `state-history`
+#[derive(Debug, serde::Serialize)]
+pub struct StateChange {
+    /// RFC 3339 UTC timestamp of the save
+    pub saved_at: String,
+    pub user: String,
+    pub protected: Vec<u32>,
+    pub unprotected: Vec<u32>,
+}
+
+/// Compare two sets of protected generations
+/// Returns the generations protected and unprotected going from `before` to `after`, sorted
+fn protection_changes(before: &HashSet<u32>, after: &HashSet<u32>) -> (Vec<u32>, Vec<u32>) {
+    let mut protected: Vec<u32> = after.difference(before).copied().collect();
+    let mut unprotected: Vec<u32> = before.difference(after).copied().collect();
+    protected.sort_unstable();
+    unprotected.sort_unstable();
+    (protected, unprotected)
+}
+
+/// Print the effective settings, merged from all config files and flags
+///
+/// Text output is TOML that can be copied into a config file, preceded by the
+/// files the settings were loaded from and the state file in use.
+///
+/// # Arguments
+///
+/// * `config` - The merged settings
+/// * `state_file` - The state file the protections are kept in
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the settings cannot be serialized
+pub fn show_config(config: &Config, state_file: &StateFile) -> Result<()> {
+    let effective = config.clone().with_defaults();
+
+    if effective.output() == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+        return Ok(());
+    }
+
+    if effective.sources.is_empty() {
+        println!("# No config files found, using defaults");
+    }
+    for source in &effective.sources {
+        println!("# Loaded from {}", source.display());
+    }
+    println!("# State file: {}", state_file.path().display());
+    print!("{}", toml::to_string(&effective)?);
+    Ok(())
+}
+
+/// Pick the runner matching how the profile is managed, `nix profile` or nix-env
+/// Without the `nix-profile` feature, every profile is treated as a nix-env profile
+pub fn runner_for_profile(
+    profile: &str,
+    parse_mode: ParseMode,
+    nix: NixCommand,
+) -> Box<dyn NixOsCommandRunner> {
+    #[cfg(feature = "nix-profile")]
+    if nix_profile_runner::is_nix_profile(Path::new(profile)) {
+        return Box::new(
+            NixProfileRunner::with_profile(profile.to_string())
+                .with_parse_mode(parse_mode)
+                .with_nix_command(nix),
+        );
+    }
+
+    Box::new(
+        RealNixOsRunner::with_profile(profile.to_string())
+            .with_parse_mode(parse_mode)
+            .with_nix_command(nix),
+    )
+}
+
+/// Run a hook command from config.toml with `sh -c`
+/// The profile is passed in `LOCK_GENERATIONS_PROFILE`; a failing hook is an error
+pub fn run_hook(name: &str, command: Option<&str>, runner: &dyn NixOsCommandRunner) -> Result<()> {
+    let Some(command) = command else {
+        return Ok(());
+    };
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("LOCK_GENERATIONS_PROFILE", runner.profile_path())
+        .status()
+        .with_context(|| format!("Failed to run {} hook", name))?;
+
+    if !status.success() {
+        anyhow::bail!("{} hook `{}` failed: {}", name, command, status);
+    }
+    Ok(())
+}
+
+/// Record the result of a protect, unprotect or clean action in the audit log
+/// `Ok(true)` means something changed, `Ok(false)` that there was nothing to do
+fn record_action(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    action: AuditAction,
+    generations: &[u32],
+    result: &Result<bool>,
+) {
+    let outcome = match result {
+        Ok(true) => AuditOutcome::Success,
+        Ok(false) => AuditOutcome::Unchanged,
+        Err(_) => AuditOutcome::Failed,
+    };
+    let mut entry = AuditEntry::new(
+        action,
+        runner.profile_path(),
+        audited_generations(runner, generations),
+        outcome,
+    );
+    if let Err(e) = result {
+        entry = entry.failed(e);
+    }
+    audit.record(&entry);
+}
+
+/// Record an action whose state change is saved before its side effects run
+/// A failed side effect does not undo the saved change, so the entry only counts as
+/// failed if nothing changed at all; the side effect's error is attached either way
+fn record_saved_action(
+    runner: &dyn NixOsCommandRunner,
+    audit: &AuditLog,
+    action: AuditAction,
+    generations: &[u32],
+    saved: &Result<bool>,
+    side_effects: &Result<bool>,
+) {
+    let outcome = match (saved, side_effects) {
+        (Err(_), _) | (Ok(false), Err(_)) => AuditOutcome::Failed,
+        (Ok(true), _) | (Ok(false), Ok(true)) => AuditOutcome::Success,
+        (Ok(false), Ok(false)) => AuditOutcome::Unchanged,
+    };
+    let mut entry = AuditEntry::new(
+        action,
+        runner.profile_path(),
+        audited_generations(runner, generations),
+        outcome,
+    );
+    if let Err(e) = saved.as_ref().and(side_effects.as_ref()) {
+        entry.error = Some(format!("{:#}", e));
+    }
+    audit.record(&entry);
+}
+
+/// Look up the store paths of generations for the audit log
+/// Generations whose store path cannot be resolved are recorded without one
+fn audited_generations(
+    runner: &dyn NixOsCommandRunner,
+    generations: &[u32],
+) -> Vec<AuditedGeneration> {
+    generations
+        .iter()
+        .map(|&number| AuditedGeneration {
+            number,
+            store_path: runner.generation_store_path(number).ok(),
+            outcome: None,
+            error: None,
+        })
+        .collect()
+}
+
+/// Repair drift between the protected generations and the registered GC roots
+///
+/// Every protected generation that still exists gets a GC root pointing at its store
+/// path, and roots for generations that are no longer protected are removed.
+///
+/// # Arguments
+///
+/// * `runner` - The command runner used to query and modify GC roots
+/// * `state_file` - The state file the protections of the invoking user are kept in
+/// * `all_users` - If true, the protections of every user are pinned too, as root does;
+///   otherwise their roots would be removed
+/// * `dry_run` - If true, only reports the changes that would be made
+///
+/// # Returns
+///
+/// Returns `Ok(())` on success, or an error if the state or the roots cannot be accessed
+pub fn sync_roots(
+    runner: &dyn NixOsCommandRunner,
+    state_file: &StateFile,
+    all_users: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let state = load_protections(state_file, all_users)?;
+    let changes = sync_gc_roots(runner, &state, dry_run)?;
+
+    let prefix = if dry_run {
+        "[DRY RUN] Would add"
+    } else {
+        "Added"
+    };
+    for generation in &changes.added {
+        println!("{} GC root for generation {}", prefix, generation);
+    }
+    let prefix = if dry_run {
+        "[DRY RUN] Would remove"
+    } else {
+        "Removed"
+    };
+    for generation in &changes.removed {
+        println!("{} GC root for generation {}", prefix, generation);
+    }
+    for generation in &changes.missing {
+        eprintln!(
+            "Warning: protected generation {} no longer exists, cannot pin it",
+            generation
+        );
+    }
+
+    if changes.added.is_empty() && changes.removed.is_empty() {
+        println!("GC roots are in sync");
+    }
+
+    Ok(())
+}
+
+/// Changes made (or planned) by `sync_gc_roots`
+#[derive(Debug, Default)]
+struct RootChanges {
+    added: Vec<u32>,
+    removed: Vec<u32>,
+    missing: Vec<u32>,
+}
+
+/// Bring the GC roots of the runner's profile in line with the protected state
+fn sync_gc_roots(
+    runner: &dyn NixOsCommandRunner,
+    state: &ProtectedState,
+    dry_run: bool,
+) -> Result<RootChanges> {
+    let mut changes = RootChanges::default();
+
+    // Only look at the roots that belong to this profile
+    let prefix = format!("{}-", profile_key(runner));
+    let existing: Vec<(u32, std::path::PathBuf)> = runner
+        .list_gc_roots()?
+        .into_iter()
+        .filter_map(|root| {
+            let generation = root.name.strip_prefix(&prefix)?.parse::<u32>().ok()?;
+            Some((generation, root.store_path))
+        })
+        .collect();
+
+    for (generation, _) in &existing {
+        if !state.is_protected(*generation) {
+            if !dry_run {
+                runner.remove_gc_root(&gc_root_name(runner, *generation))?;
+            }
+            changes.removed.push(*generation);
+        }
+    }
+
+    for generation in state.all_protected() {
+        let Ok(store_path) = runner.generation_store_path(generation) else {
+            changes.missing.push(generation);
+            continue;
+        };
+        let up_to_date = existing
+            .iter()
+            .any(|(g, path)| *g == generation && *path == store_path);
+        if !up_to_date {
+            if !dry_run {
+                runner.add_gc_root(&gc_root_name(runner, generation), &store_path)?;
+            }
+            changes.added.push(generation);
+        }
+    }
+
+    changes.removed.sort_unstable();
+    Ok(changes)
+}
+
+/// The runner's profile path as a single file name, escaped like `systemd-escape --path`,
+/// e.g. "nix-var-nix-profiles-system" for /nix/var/nix/profiles/system
+///
+/// Distinct profiles get distinct names, even ones with the same base name such as
+/// the `profile` of two users, so their GC roots never clash.
+fn profile_key(runner: &dyn NixOsCommandRunner) -> String {
+    let path = runner.profile_path().trim_matches('/');
+    let mut key = String::new();
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'/' => key.push('-'),
+            b'.' if i > 0 => key.push('.'),
+            b if b.is_ascii_alphanumeric() || b == b'_' || b == b':' => key.push(b as char),
+            b => key.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    key
+}
+
+/// Name of the GC root that pins a generation, e.g. "nix-var-nix-profiles-system-42"
+fn gc_root_name(runner: &dyn NixOsCommandRunner, generation: u32) -> String {
+    format!("{}-{}", profile_key(runner), generation)
+}
+
+/// Name of the GC root that keeps a quarantined generation alive,
+/// e.g. "quarantine-nix-var-nix-profiles-system-42"
+fn quarantine_root_name(runner: &dyn NixOsCommandRunner, generation: u32) -> String {
+    format!("quarantine-{}", gc_root_name(runner, generation))
+}
+
+/// Name of the dedicated profile that pins a generation, e.g. "keep-42" for the system
+/// profile and "keep-home-alice-.local-state-nix-profiles-profile-42" for any other
+///
+/// Only the system profile's keep profiles show up in the bootloader menu, so they keep
+/// the short name; other profiles are told apart by their escaped path.
+fn keep_profile_name(runner: &dyn NixOsCommandRunner, generation: u32) -> String {
+    if runner.profile_path() == DEFAULT_PROFILE_PATH {
+        format!("keep-{}", generation)
+    } else {
+        format!("keep-{}", gc_root_name(runner, generation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_runner::MockNixOsRunner;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Logs and a state file in a temporary directory, without a system layer
+    fn test_files() -> (TempDir, AuditLog, RecoveryLog, StateFile) {
+        let tmp_dir = TempDir::new().unwrap();
+        let audit = AuditLog::new(tmp_dir.path().join("audit.log"));
+        let recovery = RecoveryLog::new(tmp_dir.path().join("deleted.json"));
+        let state_file = StateFile::new(tmp_dir.path().join("protected.json"), StateMode::User)
+            .with_system_dir(None);
+        (tmp_dir, audit, recovery, state_file)
+    }
+
+    #[test]
+    fn test_clean_no_protected() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Should delete all except current (5)
+        assert!(runner.was_deleted(1));
+        assert!(runner.was_deleted(2));
+        assert!(runner.was_deleted(3));
+        assert!(runner.was_deleted(4));
+        assert!(!runner.was_deleted(5));
+    }
+
+    #[test]
+    fn test_clean_with_keep_last() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                policy: RetentionPolicy::keep_last(2),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Should delete 1, 2, 3 and keep 4, 5 (last 2)
+        assert!(runner.was_deleted(1));
+        assert!(runner.was_deleted(2));
+        assert!(runner.was_deleted(3));
+        assert!(!runner.was_deleted(4));
+        assert!(!runner.was_deleted(5));
+    }
+
+    #[test]
+    fn test_clean_with_older_than() {
+        let now = timestamp::now();
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4], 4).with_created(&[
+            (1, now - 30 * 86_400),
+            (2, now - 20 * 86_400),
+            (3, now - 86_400),
+            (4, now),
+        ]);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let policy = RetentionPolicy {
+            name: Some("laptop".to_string()),
+            older_than: Some("14d".parse().unwrap()),
+            ..Default::default()
+        };
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                policy: policy.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        assert!(runner.was_deleted(1));
+        assert!(runner.was_deleted(2));
+        assert!(!runner.was_deleted(3));
+        assert!(!runner.was_deleted(4));
+
+        let explained =
+            explain_generations(&runner, &state_file, &policy, false, OutputFormat::Json).unwrap();
+        let reasons: Vec<_> = explained
+            .iter()
+            .map(|e| (e.generation, e.reason.as_deref()))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (3, Some("older_than = 14d (policy laptop)")),
+                (4, Some("current generation"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clean_dry_run() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Dry run should not delete anything
+        assert!(!runner.was_deleted(1));
+        assert!(!runner.was_deleted(2));
+        assert!(!runner.was_deleted(3));
+        assert!(!runner.was_deleted(4));
+        assert!(!runner.was_deleted(5));
+    }
+
+    #[test]
+    fn test_clean_with_protected_generations() {
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        // Create and save protected state
+        let mut state = ProtectedState::new();
+        state.protect(2);
+        state.protect(4);
+        state_file.save(&state).unwrap();
+
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Should delete 1, 3 but keep 2, 4 (protected) and 5 (current)
+        assert!(runner.was_deleted(1));
+        assert!(!runner.was_deleted(2)); // protected
+        assert!(runner.was_deleted(3));
+        assert!(!runner.was_deleted(4)); // protected
+        assert!(!runner.was_deleted(5)); // current
+    }
+
+    #[test]
+    fn test_clean_all_users() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        let (log_dir, audit, recovery, state_file) = test_files();
+        // Alice protected 2 in her own file, 3 is protected in the shared state
+        let alice = log_dir.path().join("home").join("alice");
+        let mut state = ProtectedState::new();
+        state.protect(2);
+        state
+            .save_to(&alice.join(".config/lock-generations/protected.json"))
+            .unwrap();
+        let shared = log_dir.path().join("state.json");
+        let mut state = ProtectedState::new();
+        state.protect(3);
+        state.save_to(&shared).unwrap();
+        let bob = log_dir.path().join("home").join("bob");
+        let state_file = state_file.with_all_users(shared, vec![alice, bob]);
+
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                all_users: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        assert!(runner.was_deleted(1));
+        assert!(!runner.was_deleted(2));
+        assert!(!runner.was_deleted(3));
+        assert!(runner.was_deleted(4));
+    }
+
+    #[test]
+    fn test_clean_with_protected_and_keep_last() {
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        let mut state = ProtectedState::new();
+        state.protect(2);
+        state_file.save(&state).unwrap();
+
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5, 6], 6);
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                policy: RetentionPolicy::keep_last(3),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Should delete 1, 3
+        // Keep: 2 (protected), 4, 5, 6 (last 3)
+        assert!(runner.was_deleted(1));
+        assert!(!runner.was_deleted(2)); // protected
+        assert!(runner.was_deleted(3));
+        assert!(!runner.was_deleted(4)); // keep_last 3
+        assert!(!runner.was_deleted(5)); // keep_last 3
+        assert!(!runner.was_deleted(6)); // keep_last 3 + current
+    }
+
+    #[test]
+    fn test_clean_no_generations_to_delete() {
+        let runner = MockNixOsRunner::with_current(vec![5], 5);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let result = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        );
+
+        // Should succeed with nothing to delete
+        assert!(result.is_ok());
+        assert!(!runner.was_deleted(5));
+    }
+
+    #[test]
+    fn test_clean_all_protected() {
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        let mut state = ProtectedState::new();
+        state.protect(1);
+        state.protect(2);
+        state.protect(3);
+        state.protect(4);
+        state_file.save(&state).unwrap();
+
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Nothing should be deleted (all protected or current)
+        assert!(!runner.was_deleted(1));
+        assert!(!runner.was_deleted(2));
+        assert!(!runner.was_deleted(3));
+        assert!(!runner.was_deleted(4));
+        assert!(!runner.was_deleted(5));
+    }
+
+    #[test]
+    fn test_clean_keep_last_exceeds_total() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                policy: RetentionPolicy::keep_last(10),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Keep_last is larger than total, so keep everything
+        assert!(!runner.was_deleted(1));
+        assert!(!runner.was_deleted(2));
+        assert!(!runner.was_deleted(3));
+    }
+
+    #[test]
+    fn test_clean_non_sequential_generations() {
+        let runner = MockNixOsRunner::with_current(vec![1, 3, 5, 7, 10], 10);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                policy: RetentionPolicy::keep_last(2),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Should keep last 2: 7, 10
+        assert!(runner.was_deleted(1));
+        assert!(runner.was_deleted(3));
+        assert!(runner.was_deleted(5));
+        assert!(!runner.was_deleted(7)); // keep_last 2
+        assert!(!runner.was_deleted(10)); // current
+    }
+
+    #[test]
+    fn test_clean_current_not_last_generation() {
+        // Scenario: User rolled back from generation 5 to generation 3
+        // Generations 4 and 5 exist but are newer than current
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Should delete everything except current (3)
+        assert!(runner.was_deleted(1));
+        assert!(runner.was_deleted(2));
+        assert!(!runner.was_deleted(3)); // current
+        assert!(runner.was_deleted(4)); // newer than current, but not current
+        assert!(runner.was_deleted(5)); // newer than current, but not current
+    }
+
+    #[test]
+    fn test_sync_gc_roots() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        runner
+            .add_gc_root(
+                "nix-var-nix-profiles-system-1",
+                &MockNixOsRunner::store_path_for(1),
+            )
+            .unwrap();
+        runner
+            .add_gc_root(
+                "nix-var-nix-profiles-system-2",
+                &MockNixOsRunner::store_path_for(2),
+            )
+            .unwrap();
+        // Roots of other profiles are left alone
+        runner
+            .add_gc_root("other-1", &MockNixOsRunner::store_path_for(1))
+            .unwrap();
+
+        let mut state = ProtectedState::new();
+        state.protect(2);
+        state.protect(4);
+        state.protect(9);
+
+        let changes = sync_gc_roots(&runner, &state, false).unwrap();
+        assert_eq!(changes.added, vec![4]);
+        assert_eq!(changes.removed, vec![1]);
+        assert_eq!(changes.missing, vec![9]);
+
+        assert!(!runner.has_gc_root("nix-var-nix-profiles-system-1"));
+        assert!(runner.has_gc_root("nix-var-nix-profiles-system-2"));
+        assert!(runner.has_gc_root("nix-var-nix-profiles-system-4"));
+        assert!(runner.has_gc_root("other-1"));
+    }
+
+    #[test]
+    fn test_sync_gc_roots_dry_run() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        runner
+            .add_gc_root(
+                "nix-var-nix-profiles-system-1",
+                &MockNixOsRunner::store_path_for(1),
+            )
+            .unwrap();
+
+        let mut state = ProtectedState::new();
+        state.protect(2);
+
+        let changes = sync_gc_roots(&runner, &state, true).unwrap();
+        assert_eq!(changes.added, vec![2]);
+        assert_eq!(changes.removed, vec![1]);
+
+        // Nothing should have changed
+        assert!(runner.has_gc_root("nix-var-nix-profiles-system-1"));
+        assert!(!runner.has_gc_root("nix-var-nix-profiles-system-2"));
+    }
+
+    #[test]
+    fn test_sync_roots_keeps_other_users_protections() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (log_dir, _audit, _recovery, state_file) = test_files();
+        for generation in [1, 2] {
+            runner
+                .add_gc_root(
+                    &gc_root_name(&runner, generation),
+                    &MockNixOsRunner::store_path_for(generation),
+                )
+                .unwrap();
+        }
+        // Generation 2 is protected by another admin, not by the invoking user
+        let alice = log_dir.path().join("home").join("alice");
+        let mut state = ProtectedState::new();
+        state.protect(2);
+        state
+            .save_to(&alice.join(".config/lock-generations/protected.json"))
+            .unwrap();
+        let state_file = state_file.with_all_users(log_dir.path().join("state.json"), vec![alice]);
+
+        sync_roots(&runner, &state_file, true, false).unwrap();
+        assert!(!runner.has_gc_root(&gc_root_name(&runner, 1)));
+        assert!(runner.has_gc_root(&gc_root_name(&runner, 2)));
+    }
+
+    #[test]
+    fn test_gc_root_names_are_per_profile() {
+        let alice = MockNixOsRunner::with_current(vec![1], 1)
+            .with_profile_path("/home/alice/.local/state/nix/profiles/profile");
+        let bob = MockNixOsRunner::with_current(vec![1], 1)
+            .with_profile_path("/home/bob/.local/state/nix/profiles/profile");
+        assert_eq!(
+            gc_root_name(&alice, 5),
+            "home-alice-.local-state-nix-profiles-profile-5"
+        );
+        assert_ne!(gc_root_name(&alice, 5), gc_root_name(&bob, 5));
+
+        // Dashes in the path are escaped, so names cannot be confused across profiles
+        let dashed = MockNixOsRunner::with_current(vec![1], 1)
+            .with_profile_path("/nix/var/nix/profiles/per-user/root/.profile");
+        assert_eq!(
+            gc_root_name(&dashed, 5),
+            "nix-var-nix-profiles-per\\x2duser-root-.profile-5"
+        );
+    }
+
+    #[test]
+    fn test_protect_and_unprotect_gc_root() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, _recovery, state_file) = test_files();
+        let root = gc_root_name(&runner, 2);
+
+        protect_generation(&runner, &audit, &state_file, 2, true, false).unwrap();
+        assert!(runner.has_gc_root(&root));
+        assert!(state_file.load().unwrap().is_protected(2));
+
+        unprotect_generation(&runner, &audit, &state_file, 2).unwrap();
+        assert!(!runner.has_gc_root(&root));
+        assert!(!state_file.load().unwrap().is_protected(2));
+    }
+
+    #[test]
+    fn test_failed_gc_root_is_audited_with_the_saved_protection() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3).fail_gc_root_after(0);
+        let (_log_dir, audit, _recovery, state_file) = test_files();
+
+        assert!(protect_generation(&runner, &audit, &state_file, 2, true, false).is_err());
+
+        // The protection is on disk, so the entry says so despite the error
+        assert!(state_file.load().unwrap().is_protected(2));
+        let entry = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(entry.outcome, AuditOutcome::Success);
+        assert!(entry.error.unwrap().contains("GC root"));
+
+        // Nothing changed the second time around
+        assert!(protect_generation(&runner, &audit, &state_file, 2, true, false).is_err());
+        let entry = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(entry.outcome, AuditOutcome::Failed);
+    }
+
+    #[test]
+    fn test_protect_and_unprotect_pin_profile() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, _recovery, state_file) = test_files();
+        let name = "keep-2";
+        assert_eq!(keep_profile_name(&runner, 2), name);
+
+        protect_generation(&runner, &audit, &state_file, 2, false, true).unwrap();
+        assert!(runner.has_pinned_profile(name));
+
+        unprotect_generation(&runner, &audit, &state_file, 2).unwrap();
+        assert!(!runner.has_pinned_profile(name));
+    }
+
+    #[test]
+    fn test_keep_profile_names_are_per_profile() {
+        let system = MockNixOsRunner::with_current(vec![5], 5);
+        let user = MockNixOsRunner::with_current(vec![5], 5)
+            .with_profile_path("/home/alice/.local/state/nix/profiles/profile");
+        assert_eq!(keep_profile_name(&system, 5), "keep-5");
+        assert_eq!(
+            keep_profile_name(&user, 5),
+            "keep-home-alice-.local-state-nix-profiles-profile-5"
+        );
+    }
+
+    #[test]
+    fn test_unprotect_refuses_system_layer() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (log_dir, audit, _recovery, state_file) = test_files();
+        let system_dir = log_dir.path().join("etc");
+        let mut system = ProtectedState::new();
+        system.protect(2);
+        system.save_to(&system_dir.join("protected.json")).unwrap();
+        let state_file = state_file.with_system_dir(Some(system_dir));
+        protect_generation(&runner, &audit, &state_file, 1, false, false).unwrap();
+        let before = fs::read_to_string(state_file.path()).unwrap();
+
+        let err = unprotect_generation(&runner, &audit, &state_file, 2).unwrap_err();
+        assert!(err.to_string().contains("system layer"));
+
+        // Neither the user's file nor the merged state changed
+        assert_eq!(fs::read_to_string(state_file.path()).unwrap(), before);
+        let state = state_file.load().unwrap();
+        assert!(state.is_protected(1));
+        assert!(state.is_protected(2));
+    }
+
+    #[test]
+    fn test_clean_records_audit_entry() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        let entries = audit.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, AuditAction::Clean);
+        assert_eq!(entries[0].outcome, AuditOutcome::Success);
+        assert_eq!(
+            entries[0].generations,
+            vec![
+                AuditedGeneration {
+                    number: 1,
+                    store_path: Some(MockNixOsRunner::store_path_for(1)),
+                    outcome: Some(AuditOutcome::Success),
+                    error: None,
+                },
+                AuditedGeneration {
+                    number: 2,
+                    store_path: Some(MockNixOsRunner::store_path_for(2)),
+                    outcome: Some(AuditOutcome::Success),
+                    error: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clean_records_failed_deletion() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3).fail_on_delete();
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let report = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap();
+        assert!(report.deleted.is_empty());
+        assert_eq!(
+            report.failed.keys().copied().collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let entries = audit.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outcome, AuditOutcome::Failed);
+        assert!(entries[0].involves(1));
+    }
+
+    #[test]
+    fn test_clean_only_keeps_records_of_deleted_generations() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5, 6], 6).fail_on_generation(3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        // The batch [3, 4] fails, [5] is still deleted
+        let options = CleanOptions {
+            batch_size: 2,
+            keep_going: true,
+            ..Default::default()
+        };
+        let report = clean_generations(&runner, &audit, &recovery, &state_file, &options).unwrap();
+        assert_eq!(report.deleted, vec![1, 2, 5]);
+
+        let recorded: Vec<u32> = recovery
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|e| e.generation)
+            .collect();
+        assert_eq!(recorded, vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn test_clean_dry_run_not_audited() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                dry_run: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        assert!(audit.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_after_clean() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+        assert!(runner.was_deleted(1));
+
+        restore_generation(&runner, &audit, &recovery, 1).unwrap();
+        assert!(!runner.was_deleted(1));
+        assert!(runner.was_deleted(2));
+
+        // The record is consumed, restoring twice fails
+        assert!(restore_generation(&runner, &audit, &recovery, 1).is_err());
+        assert_eq!(
+            audit.entries().unwrap().last().unwrap().action,
+            AuditAction::Restore
+        );
+    }
+
+    #[test]
+    fn test_restore_after_garbage_collection() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+        runner.collect_garbage(2);
+
+        let err = restore_generation(&runner, &audit, &recovery, 2).unwrap_err();
+        assert!(err.to_string().contains("garbage collected"));
+        assert!(runner.was_deleted(2));
+
+        let last = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(last.outcome, AuditOutcome::Failed);
+    }
+
+    #[test]
+    fn test_restore_unknown_generation() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, _) = test_files();
+
+        assert!(restore_generation(&runner, &audit, &recovery, 1).is_err());
+    }
+
+    #[test]
+    fn test_clean_quarantine() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                quarantine: Some(7),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        // Generations leave the profile but their closures stay pinned
+        assert!(runner.was_deleted(1));
+        assert!(runner.was_deleted(2));
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-2"));
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-3"));
+
+        // Still within the grace period, nothing is released
+        purge_quarantine(&runner, &audit, &recovery, false, false).unwrap();
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+
+        // Restoring releases the quarantine root of that generation only
+        restore_generation(&runner, &audit, &recovery, 1).unwrap();
+        assert!(!runner.was_deleted(1));
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-2"));
+
+        // Released generations are forgotten, they are gone with the next GC
+        purge_quarantine(&runner, &audit, &recovery, true, false).unwrap();
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-2"));
+        assert!(recovery.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clean_quarantine_releases_surviving_generations() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).fail_on_generation(3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let options = CleanOptions {
+            quarantine: Some(7),
+            ..Default::default()
+        };
+        let report = clean_generations(&runner, &audit, &recovery, &state_file, &options).unwrap();
+        assert!(report.check().is_err());
+
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-2"));
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-3"));
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-4"));
+    }
+
+    #[test]
+    fn test_clean_undoes_a_failed_quarantine() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4], 4).fail_gc_root_after(2);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let options = CleanOptions {
+            quarantine: Some(7),
+            ..Default::default()
+        };
+
+        let err = clean_generations(&runner, &audit, &recovery, &state_file, &options).unwrap_err();
+        assert!(err.to_string().contains("nothing was deleted"));
+        assert!(!runner.was_deleted(1));
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-2"));
+        assert!(recovery.entries().unwrap().is_empty());
+        let last = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(last.action, AuditAction::Clean);
+        assert_eq!(last.outcome, AuditOutcome::Failed);
+    }
+
+    #[test]
+    fn test_purge_quarantine_dry_run() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2], 2);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                quarantine: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        purge_quarantine(&runner, &audit, &recovery, false, true).unwrap();
+        assert!(runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+
+        // A zero-day grace period expires immediately
+        purge_quarantine(&runner, &audit, &recovery, false, false).unwrap();
+        assert!(!runner.has_gc_root("quarantine-nix-var-nix-profiles-system-1"));
+    }
+
+    #[test]
+    fn test_run_hook() {
+        let runner = MockNixOsRunner::new(vec![1]);
+
+        run_hook("pre_clean", None, &runner).unwrap();
+        run_hook(
+            "pre_clean",
+            Some("test -n \"$LOCK_GENERATIONS_PROFILE\""),
+            &runner,
+        )
+        .unwrap();
+
+        let err = run_hook("pre_clean", Some("exit 3"), &runner).unwrap_err();
+        assert!(err.to_string().contains("pre_clean hook `exit 3` failed"));
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4], 4);
+        let (log_dir, audit, _, state_file) = test_files();
+        let mut state = ProtectedState::new();
+        state.protect_as(2, "alice");
+        state.protect(3);
+        state_file.save(&state).unwrap();
+
+        let export_path = log_dir.path().join("export.json");
+        export_protections(&runner, &state_file, Some(&export_path)).unwrap();
+        let export = ExportFile::read(&export_path).unwrap();
+        assert_eq!(export.protections.len(), 2);
+        assert_eq!(
+            export.protections[0].store_path,
+            Some(MockNixOsRunner::store_path_for(2))
+        );
+        assert_eq!(export.protections[0].protected_by.as_deref(), Some("alice"));
+
+        // Import into a fresh state file
+        let (_other_dir, _, _, other_state) = test_files();
+        import_protections(
+            &runner,
+            &audit,
+            &other_state,
+            &export_path,
+            &ImportOptions::default(),
+        )
+        .unwrap();
+
+        let imported = other_state.load().unwrap();
+        assert!(imported.is_protected(2));
+        assert!(imported.is_protected(3));
+        assert_eq!(imported.protected_by(2), Some("alice"));
+        let last = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(last.action, AuditAction::Import);
+    }
+
+    #[test]
+    fn test_import_skips_mismatched_store_paths() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (log_dir, audit, _, state_file) = test_files();
+        let export = ExportFile::new(
+            runner.profile_path(),
+            vec![
+                ExportedProtection {
+                    generation: 1,
+                    store_path: Some(MockNixOsRunner::store_path_for(1)),
+                    protected_by: None,
+                },
+                ExportedProtection {
+                    generation: 2,
+                    store_path: Some(PathBuf::from("/nix/store/other-nixos-system")),
+                    protected_by: None,
+                },
+                ExportedProtection {
+                    generation: 9,
+                    store_path: None,
+                    protected_by: None,
+                },
+            ],
+        );
+        let export_path = log_dir.path().join("export.json");
+        export.write(&export_path).unwrap();
+
+        import_protections(
+            &runner,
+            &audit,
+            &state_file,
+            &export_path,
+            &ImportOptions::default(),
+        )
+        .unwrap();
+        let state = state_file.load().unwrap();
+        assert!(state.is_protected(1));
+        assert!(!state.is_protected(2));
+        assert!(!state.is_protected(9));
+
+        // With force, the mismatching generations are imported as well
+        import_protections(
+            &runner,
+            &audit,
+            &state_file,
+            &export_path,
+            &ImportOptions {
+                force: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let state = state_file.load().unwrap();
+        assert!(state.is_protected(2));
+        assert!(state.is_protected(9));
+    }
+
+    #[test]
+    fn test_import_replace() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (log_dir, audit, _, state_file) = test_files();
+        let mut state = ProtectedState::new();
+        state.protect(1);
+        state_file.save(&state).unwrap();
+
+        let export = ExportFile::new(
+            runner.profile_path(),
+            vec![ExportedProtection {
+                generation: 2,
+                store_path: Some(MockNixOsRunner::store_path_for(2)),
+                protected_by: None,
+            }],
+        );
+        let export_path = log_dir.path().join("export.json");
+        export.write(&export_path).unwrap();
+
+        // A dry run changes nothing
+        let options = ImportOptions {
+            replace: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        import_protections(&runner, &audit, &state_file, &export_path, &options).unwrap();
+        assert!(state_file.load().unwrap().is_protected(1));
+
+        let options = ImportOptions {
+            replace: true,
+            ..Default::default()
+        };
+        import_protections(&runner, &audit, &state_file, &export_path, &options).unwrap();
+        let state = state_file.load().unwrap();
+        assert!(!state.is_protected(1));
+        assert!(state.is_protected(2));
+    }
+
+    #[test]
+    fn test_undo_unprotect() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, _, state_file) = test_files();
+
+        protect_generation(&runner, &audit, &state_file, 1, false, false).unwrap();
+        protect_generation(&runner, &audit, &state_file, 2, false, false).unwrap();
+        unprotect_generation(&runner, &audit, &state_file, 1).unwrap();
+        assert!(!state_file.load().unwrap().is_protected(1));
+
+        undo_change(&runner, &audit, &state_file).unwrap();
+        let state = state_file.load().unwrap();
+        assert!(state.is_protected(1));
+        assert!(state.is_protected(2));
+        let last = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(last.action, AuditAction::Undo);
+        assert!(last.involves(1));
+
+        // Undoing all the way back reaches the state before the first protect
+        undo_change(&runner, &audit, &state_file).unwrap();
+        undo_change(&runner, &audit, &state_file).unwrap();
+        assert!(state_file.load().unwrap().all_protected().is_empty());
+        undo_change(&runner, &audit, &state_file).unwrap();
+        assert_eq!(
+            audit.entries().unwrap().pop().unwrap().outcome,
+            AuditOutcome::Unchanged
+        );
+    }
+
+    #[test]
+    fn test_state_history_size() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, _, state_file) = test_files();
+        let state_file = state_file.with_history_size(2);
+
+        for generation in 1..=3 {
+            protect_generation(&runner, &audit, &state_file, generation, false, false).unwrap();
+        }
+        assert_eq!(state_file.history().unwrap().len(), 2);
+
+        // Only the two most recent protections can be traced back
+        let changes = show_state_history(&state_file, None, OutputFormat::Json).unwrap();
+        let protected: Vec<_> = changes.iter().map(|c| c.protected.clone()).collect();
+        assert_eq!(protected, vec![vec![3], vec![2]]);
+        assert!(changes.iter().all(|c| c.unprotected.is_empty()));
+
+        let changes = show_state_history(&state_file, Some(1), OutputFormat::Json).unwrap();
+        assert_eq!(changes.len(), 1);
+    }
+
+    #[test]
+    fn test_clean_aborts_when_generations_change() {
+        // A rebuild creates generation 6 while the clean is being planned
+        let runner =
+            MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).rebuild_after_first_snapshot(6);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        let err = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("nothing was deleted"));
+        // The mismatch itself stays in the chain
+        assert!(format!("{:#}", err).contains("Generations changed from"));
+        assert_eq!(crate::error::exit_code(&err), ErrorKind::Busy.exit_code());
+        assert!(!runner.was_deleted(1));
+        assert!(recovery.entries().unwrap().is_empty());
+        let last = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(last.outcome, AuditOutcome::Failed);
+    }
+
+    #[test]
+    fn test_clean_refuses_during_rebuild() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).rebuilding_for(1);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        let err = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("--wait"));
+        assert_eq!(crate::error::exit_code(&err), ErrorKind::Busy.exit_code());
+        assert!(!runner.was_deleted(1));
+    }
+
+    #[test]
+    fn test_clean_waits_for_rebuild() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).rebuilding_for(3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                wait: true,
+                poll_interval: Duration::from_millis(1),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        assert!(runner.was_deleted(1));
+        assert_eq!(runner.rebuild_in_progress().unwrap(), None);
+    }
+
+    #[test]
+    fn test_clean_deletes_in_batches() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                batch_size: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        assert_eq!(runner.delete_batches(), vec![vec![1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn test_clean_refuses_zero_batch_size() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let err = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                batch_size: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(crate::error::kind_of(&err), Some(ErrorKind::Policy));
+        assert!(!runner.was_deleted(1));
+        assert!(audit.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clean_reports_what_actually_went_away() {
+        // nix-env deletes generation 1, then fails on 2; the batch of 3 and 4 is skipped
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).fail_on_generation(2);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let report = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                batch_size: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.deleted, vec![1]);
+        let err = report.check().unwrap_err();
+        assert!(err.to_string().contains("3 of 4"));
+        assert_eq!(crate::error::exit_code(&err), ErrorKind::Runner.exit_code());
+        assert_eq!(runner.delete_batches(), vec![vec![1, 2]]);
+        let entry = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(entry.outcome, AuditOutcome::Failed);
+        let outcomes: Vec<_> = entry
+            .generations
+            .iter()
+            .map(|g| (g.number, g.outcome.unwrap()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                (1, AuditOutcome::Success),
+                (2, AuditOutcome::Failed),
+                (3, AuditOutcome::Failed),
+                (4, AuditOutcome::Failed),
+            ]
+        );
+        assert!(
+            entry.generations[2]
+                .error
+                .as_deref()
+                .unwrap()
+                .contains("not attempted")
+        );
+    }
+
+    #[test]
+    fn test_clean_keep_going() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).fail_on_generation(1);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        let report = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions {
+                batch_size: 2,
+                keep_going: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(report.deleted, vec![3, 4]);
+        let err = report.check().unwrap_err();
+        assert!(err.to_string().contains("2 of 4"));
+        assert!(!runner.was_deleted(2));
+        assert!(runner.was_deleted(3));
+        assert!(runner.was_deleted(4));
+    }
+
+    #[test]
+    fn test_clean_keeps_booted_generation() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5).with_booted(2);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap()
+        .check()
+        .unwrap();
+
+        assert!(runner.was_deleted(1));
+        assert!(!runner.was_deleted(2));
+    }
+
+    #[test]
+    fn test_clean_detects_deleted_protected_generation() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3).also_delete(2);
+        let (_log_dir, audit, recovery, state_file) = test_files();
+        protect_generation(&runner, &audit, &state_file, 2, false, false).unwrap();
+
+        let err = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap_err();
+
+        let violation = err
+            .downcast_ref::<invariants::InvariantViolation>()
+            .unwrap();
+        assert_eq!(
+            violation.violations,
+            vec![invariants::Violation::ProtectedMissing(2)]
+        );
+        let entry = audit.entries().unwrap().pop().unwrap();
+        assert_eq!(entry.outcome, AuditOutcome::Failed);
+    }
+
+    #[test]
+    fn test_clean_detects_silently_kept_generations() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3).ignore_deletes();
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        let err = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap_err();
+
+        let violation = err
+            .downcast_ref::<invariants::InvariantViolation>()
+            .unwrap();
+        assert_eq!(
+            violation.violations,
+            vec![
+                invariants::Violation::NotDeleted(1),
+                invariants::Violation::NotDeleted(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ordinary_deletion_failure_is_no_violation() {
+        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3).fail_on_delete();
+        let (_log_dir, audit, recovery, state_file) = test_files();
+
+        // Reported as failed deletions, the profile itself checks out
+        let report = clean_generations(
+            &runner,
+            &audit,
+            &recovery,
+            &state_file,
+            &CleanOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(report.failed.len(), 2);
+    }
+}
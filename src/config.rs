@@ -0,0 +1,492 @@
+use crate::command_runner::DEFAULT_PROFILE_PATH;
+use crate::error::{ErrorKind, ResultExt};
+use crate::nix_command::NixCommand;
+use crate::paths;
+use crate::protected_state::{ProtectedState, SYSTEM_CONFIG_DIR, StateMode};
+use crate::retention::{Age, RetentionPolicy};
+use crate::state_history::DEFAULT_HISTORY_SIZE;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Number of days quarantined generations are kept unless configured otherwise
+pub const DEFAULT_GRACE_DAYS: u64 = 7;
+
+/// How commands print their results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// Machine readable JSON
+    Json,
+}
+
+/// Settings from config.toml
+///
+/// Every setting is optional, so files can be layered: the system file in
+/// /etc/lock-generations/config.toml, then the user's config.toml next to protected.json,
+/// then command-line flags. Later layers override earlier ones setting by setting.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Profile whose generations are managed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// How commands print their results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputFormat>,
+    /// Number of snapshots of the state file kept for `undo`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_history: Option<usize>,
+    /// Where protections are kept: per-user files or the state file shared by all admins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<StateMode>,
+    /// Default options of `clean`
+    pub clean: CleanConfig,
+    /// Commands run around `clean`
+    pub hooks: HooksConfig,
+    /// How the Nix binaries are invoked
+    pub nix: NixConfig,
+    /// Named retention presets, selected with `clean --policy <name>`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub policy: BTreeMap<String, RetentionPolicy>,
+    /// Files the settings were loaded from
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+/// The [clean] section of config.toml
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanConfig {
+    /// Preset from the [policy.<name>] tables used unless `--policy` says otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    /// Keep the N most recent generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    /// Only delete generations older than this, e.g. "14d"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<Age>,
+    /// Keep the newest generation of each of the last N days
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_daily: Option<usize>,
+    /// Keep the newest generation of each of the last N weeks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest generation of each of the last N months
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_monthly: Option<usize>,
+    /// Quarantine deleted generations instead of releasing them right away
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quarantine: Option<bool>,
+    /// Number of days quarantined generations are kept
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grace_days: Option<u64>,
+}
+
+impl CleanConfig {
+    /// The retention rules of this section
+    pub fn retention(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            name: None,
+            keep_last: self.keep_last,
+            older_than: self.older_than,
+            keep_daily: self.keep_daily,
+            keep_weekly: self.keep_weekly,
+            keep_monthly: self.keep_monthly,
+            ..Default::default()
+        }
+    }
+}
+
+/// The [hooks] section of config.toml
+/// Hooks are shell commands run with `sh -c`; they are skipped on dry runs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run before `clean` deletes anything; a failure aborts the clean
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_clean: Option<String>,
+    /// Run after `clean` deleted generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_clean: Option<String>,
+}
+
+/// The [nix] section of config.toml
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NixConfig {
+    /// nix-env binary to run instead of the one in PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nix_env: Option<PathBuf>,
+    /// nix binary to run instead of the one in PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nix: Option<PathBuf>,
+    /// Arguments passed to every nix-env and nix invocation, e.g. ["--store", "/mnt"]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_args: Option<Vec<String>>,
+    /// Environment variables set for every nix-env and nix invocation
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl NixConfig {
+    /// How the runners invoke the Nix binaries with these settings
+    pub fn command(&self, verbose: bool) -> NixCommand {
+        NixCommand {
+            nix_env: self.nix_env.clone(),
+            nix: self.nix.clone(),
+            extra_args: self.extra_args.clone().unwrap_or_default(),
+            env: self.env.clone(),
+            verbose,
+        }
+    }
+}
+
+impl Config {
+    /// Load and merge the system and user config files
+    /// Missing files are fine, invalid ones are an error
+    pub fn load() -> Result<Self> {
+        Self::load_layers(
+            &Path::new(SYSTEM_CONFIG_DIR).join("config.toml"),
+            &Self::user_config_path()?,
+            paths::is_root(),
+        )
+    }
+
+    /// Load the system file, then the user file on top of it
+    ///
+    /// Under sudo the user file belongs to the invoking user, who must not be able to
+    /// run commands as root through it. So when running as root, hooks and [nix]
+    /// settings of a user file not owned by root are ignored.
+    fn load_layers(system: &Path, user: &Path, as_root: bool) -> Result<Self> {
+        let config = Self::load_from(&[system.to_path_buf()])?;
+        let mut layer = Self::load_from(&[user.to_path_buf()])?;
+
+        let owned_by_root = fs::metadata(user).is_ok_and(|metadata| metadata.uid() == 0);
+        if as_root
+            && !owned_by_root
+            && (layer.hooks != HooksConfig::default() || layer.nix != NixConfig::default())
+        {
+            eprintln!(
+                "Warning: ignoring [hooks] and [nix] in {}, running as root and the file is not owned by root",
+                user.display()
+            );
+            layer.hooks = HooksConfig::default();
+            layer.nix = NixConfig::default();
+        }
+
+        Ok(config.merge(layer))
+    }
+
+    /// Load and merge config files, later files overriding earlier ones
+    pub fn load_from(files: &[PathBuf]) -> Result<Self> {
+        let mut config = Self::default();
+        for file in files {
+            if !file.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(file)
+                .with_context(|| format!("Failed to read config file: {}", file.display()))
+                .kind(ErrorKind::Policy)?;
+            let mut layer: Config = toml::from_str(&contents)
+                .with_context(|| format!("Failed to parse config file: {}", file.display()))
+                .kind(ErrorKind::Policy)?;
+            layer.sources.push(file.clone());
+            config = config.merge(layer);
+        }
+        Ok(config)
+    }
+
+    /// Get the path of the user's config file, next to protected.json
+    pub fn user_config_path() -> Result<PathBuf> {
+        Ok(ProtectedState::config_path(StateMode::User)?.with_file_name("config.toml"))
+    }
+
+    /// Layer `other` on top of this config; settings set in `other` win
+    pub fn merge(self, other: Config) -> Self {
+        Self {
+            profile: other.profile.or(self.profile),
+            output: other.output.or(self.output),
+            state_history: other.state_history.or(self.state_history),
+            mode: other.mode.or(self.mode),
+            clean: CleanConfig {
+                policy: other.clean.policy.or(self.clean.policy),
+                keep_last: other.clean.keep_last.or(self.clean.keep_last),
+                older_than: other.clean.older_than.or(self.clean.older_than),
+                keep_daily: other.clean.keep_daily.or(self.clean.keep_daily),
+                keep_weekly: other.clean.keep_weekly.or(self.clean.keep_weekly),
+                keep_monthly: other.clean.keep_monthly.or(self.clean.keep_monthly),
+                quarantine: other.clean.quarantine.or(self.clean.quarantine),
+                grace_days: other.clean.grace_days.or(self.clean.grace_days),
+            },
+            hooks: HooksConfig {
+                pre_clean: other.hooks.pre_clean.or(self.hooks.pre_clean),
+                post_clean: other.hooks.post_clean.or(self.hooks.post_clean),
+            },
+            nix: NixConfig {
+                nix_env: other.nix.nix_env.or(self.nix.nix_env),
+                nix: other.nix.nix.or(self.nix.nix),
+                extra_args: other.nix.extra_args.or(self.nix.extra_args),
+                // Variables are overridden one by one
+                env: self.nix.env.into_iter().chain(other.nix.env).collect(),
+            },
+            // A preset defined again in a later file replaces the earlier one as a whole
+            policy: self.policy.into_iter().chain(other.policy).collect(),
+            sources: [self.sources, other.sources].concat(),
+        }
+    }
+
+    /// Fill every unset setting that has a default, e.g. for `config show`
+    pub fn with_defaults(self) -> Self {
+        let defaults = Self {
+            profile: Some(DEFAULT_PROFILE_PATH.to_string()),
+            output: Some(OutputFormat::default()),
+            state_history: Some(DEFAULT_HISTORY_SIZE),
+            mode: Some(StateMode::default()),
+            clean: CleanConfig {
+                quarantine: Some(false),
+                grace_days: Some(DEFAULT_GRACE_DAYS),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        defaults.merge(self)
+    }
+
+    /// Get the profile whose generations are managed
+    pub fn profile(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE_PATH)
+    }
+
+    /// Get how commands print their results
+    pub fn output(&self) -> OutputFormat {
+        self.output.unwrap_or_default()
+    }
+
+    /// Get the number of snapshots of the state file kept for `undo`
+    pub fn state_history(&self) -> usize {
+        self.state_history.unwrap_or(DEFAULT_HISTORY_SIZE)
+    }
+
+    /// Get where protections are kept
+    pub fn mode(&self) -> StateMode {
+        self.mode.unwrap_or_default()
+    }
+
+    /// Get the retention policy `clean` applies
+    ///
+    /// The preset named by `policy` (or by the `policy` setting in [clean]) replaces the
+    /// rules of the [clean] section; without a preset, those rules apply.
+    pub fn retention_policy(&self, policy: Option<&str>) -> Result<RetentionPolicy> {
+        let Some(name) = policy.or(self.clean.policy.as_deref()) else {
+            return Ok(self.clean.retention());
+        };
+
+        let Some(preset) = self.policy.get(name) else {
+            let defined: Vec<&str> = self.policy.keys().map(String::as_str).collect();
+            return Err(anyhow::anyhow!(
+                "Unknown policy '{}' (defined policies: {})",
+                name,
+                if defined.is_empty() {
+                    "none".to_string()
+                } else {
+                    defined.join(", ")
+                }
+            ))
+            .kind(ErrorKind::Policy);
+        };
+
+        Ok(RetentionPolicy {
+            name: Some(name.to_string()),
+            ..preset.clone()
+        })
+    }
+
+    /// Get the grace period of `clean`, or None unless quarantining
+    pub fn quarantine_days(&self) -> Option<u64> {
+        self.clean
+            .quarantine
+            .unwrap_or(false)
+            .then(|| self.clean.grace_days.unwrap_or(DEFAULT_GRACE_DAYS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_and_merge_layers() {
+        let tmp_dir = TempDir::new().unwrap();
+        let system = tmp_dir.path().join("system.toml");
+        let user = tmp_dir.path().join("user.toml");
+        fs::write(
+            &system,
+            "profile = \"/nix/var/nix/profiles/per-user/alice/profile\"\n\
+             mode = \"shared\"\n\
+             [clean]\nkeep_last = 5\nquarantine = true\n\
+             [hooks]\npre_clean = \"true\"\n",
+        )
+        .unwrap();
+        fs::write(&user, "output = \"json\"\n[clean]\nkeep_last = 3\n").unwrap();
+
+        let config = Config::load_from(&[system.clone(), user.clone()]).unwrap();
+        assert_eq!(
+            config.profile(),
+            "/nix/var/nix/profiles/per-user/alice/profile"
+        );
+        assert_eq!(config.output(), OutputFormat::Json);
+        assert_eq!(config.mode(), StateMode::Shared);
+        // The user file overrides the system file setting by setting
+        assert_eq!(config.clean.keep_last, Some(3));
+        assert_eq!(config.quarantine_days(), Some(DEFAULT_GRACE_DAYS));
+        assert_eq!(config.hooks.pre_clean.as_deref(), Some("true"));
+        assert_eq!(config.sources, vec![system, user]);
+    }
+
+    #[test]
+    fn test_root_ignores_commands_of_user_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let system = tmp_dir.path().join("system.toml");
+        let user = tmp_dir.path().join("user.toml");
+        fs::write(&system, "[hooks]\npost_clean = \"true\"\n").unwrap();
+        fs::write(
+            &user,
+            "output = \"json\"\n\
+             [hooks]\npre_clean = \"id\"\n\
+             [nix]\nnix_env = \"/home/alice/nix-env\"\n",
+        )
+        .unwrap();
+        // The file of the user invoking sudo; without root the file is not root's anyway
+        let _ = std::os::unix::fs::chown(&user, Some(1000), Some(100));
+
+        let config = Config::load_layers(&system, &user, true).unwrap();
+        assert_eq!(config.output(), OutputFormat::Json);
+        assert_eq!(config.hooks.pre_clean, None);
+        assert_eq!(config.hooks.post_clean.as_deref(), Some("true"));
+        assert_eq!(config.nix, NixConfig::default());
+
+        // Without root, the user's own hooks apply
+        let config = Config::load_layers(&system, &user, false).unwrap();
+        assert_eq!(config.hooks.pre_clean.as_deref(), Some("id"));
+        assert_eq!(
+            config.nix.nix_env,
+            Some(PathBuf::from("/home/alice/nix-env"))
+        );
+    }
+
+    #[test]
+    fn test_nix_settings() {
+        let tmp_dir = TempDir::new().unwrap();
+        let system = tmp_dir.path().join("system.toml");
+        let user = tmp_dir.path().join("user.toml");
+        fs::write(
+            &system,
+            "[nix]\n\
+             nix_env = \"/run/current-system/sw/bin/nix-env\"\n\
+             extra_args = [\"--option\", \"substitute\", \"false\"]\n\
+             env = { NIX_REMOTE = \"daemon\", NIX_PATH = \"nixpkgs=/etc/nixpkgs\" }\n",
+        )
+        .unwrap();
+        fs::write(
+            &user,
+            "[nix]\n\
+             extra_args = [\"--store\", \"/mnt\"]\n\
+             env = { NIX_REMOTE = \"local\" }\n",
+        )
+        .unwrap();
+
+        let command = Config::load_from(&[system, user])
+            .unwrap()
+            .nix
+            .command(true);
+        assert_eq!(
+            command.nix_env,
+            Some(PathBuf::from("/run/current-system/sw/bin/nix-env"))
+        );
+        // The argument list is replaced as a whole, variables one by one
+        assert_eq!(command.extra_args, vec!["--store", "/mnt"]);
+        assert_eq!(command.env["NIX_REMOTE"], "local");
+        assert_eq!(command.env["NIX_PATH"], "nixpkgs=/etc/nixpkgs");
+        assert!(command.verbose);
+    }
+
+    #[test]
+    fn test_missing_files_give_defaults() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = Config::load_from(&[tmp_dir.path().join("config.toml")]).unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.profile(), DEFAULT_PROFILE_PATH);
+        assert_eq!(config.mode(), StateMode::User);
+        assert_eq!(config.quarantine_days(), None);
+    }
+
+    #[test]
+    fn test_unknown_setting_is_an_error() {
+        let tmp_dir = TempDir::new().unwrap();
+        let file = tmp_dir.path().join("config.toml");
+        fs::write(&file, "[clean]\nkeep_lats = 5\n").unwrap();
+
+        let err = Config::load_from(&[file]).unwrap_err();
+        assert!(format!("{:#}", err).contains("keep_lats"));
+    }
+
+    #[test]
+    fn test_policy_presets() {
+        let tmp_dir = TempDir::new().unwrap();
+        let file = tmp_dir.path().join("config.toml");
+        fs::write(
+            &file,
+            "[clean]\nkeep_last = 5\nkeep_daily = 7\n\
+             [policy.laptop]\nkeep_last = 3\nolder_than = \"14d\"\n\
+             [policy.server]\nkeep_monthly = 12\n",
+        )
+        .unwrap();
+        let config = Config::load_from(&[file]).unwrap();
+
+        // Without a preset, the rules of [clean] apply
+        let policy = config.retention_policy(None).unwrap();
+        assert_eq!(policy.name, None);
+        assert_eq!(policy.keep_last, Some(5));
+
+        // A preset replaces them as a whole
+        let policy = config.retention_policy(Some("laptop")).unwrap();
+        assert_eq!(policy.name.as_deref(), Some("laptop"));
+        assert_eq!(policy.keep_last, Some(3));
+        assert_eq!(policy.older_than, Some(Age::days(14)));
+        assert_eq!(policy.keep_daily, None);
+
+        let err = config.retention_policy(Some("desktop")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown policy 'desktop' (defined policies: laptop, server)"
+        );
+    }
+
+    #[test]
+    fn test_with_defaults_serializes() {
+        let config = Config {
+            clean: CleanConfig {
+                keep_last: Some(5),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .with_defaults();
+
+        let shown = toml::to_string(&config).unwrap();
+        assert!(shown.contains("keep_last = 5"));
+        assert!(shown.contains("grace_days = 7"));
+        assert!(shown.contains(DEFAULT_PROFILE_PATH));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::generation_parser::ParseError;
+use crate::invariants::InvariantViolation;
+use std::fmt;
+use std::io;
+
+/// Category of a failure, deciding the exit code scripts see
+///
+/// Errors stay `anyhow::Error` with their context for humans; the kind is
+/// attached with [`ResultExt::kind`] where an error enters the program, and
+/// [`exit_code`] looks it up again at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// nix-env or nix could not be run, failed, or changing the profile failed
+    Runner,
+    /// The output of nix-env or nix was not understood
+    Parse,
+    /// The state file or another file of this tool is unreadable, corrupt or unwritable
+    State,
+    /// A setting in config.toml or a flag is invalid, e.g. an unknown retention policy
+    Policy,
+    /// The operating system refused access, e.g. cleaning without sudo
+    PermissionDenied,
+    /// The profile is not in the state `clean` planned for
+    Invariant,
+    /// A rebuild is running or changed the generations; `clean` deleted nothing and can be
+    /// run again later
+    Busy,
+}
+
+impl ErrorKind {
+    /// Exit code of the process when a command fails with this kind of error
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ErrorKind::Invariant => 3,
+            ErrorKind::Runner => 4,
+            ErrorKind::Parse => 5,
+            ErrorKind::State => 6,
+            ErrorKind::Policy => 7,
+            ErrorKind::PermissionDenied => 8,
+            ErrorKind::Busy => 9,
+        }
+    }
+}
+
+/// Exit code of failures of no particular kind
+pub const EXIT_FAILURE: u8 = 1;
+
+/// An error tagged with its kind
+///
+/// Displays exactly like the wrapped error and continues its chain of causes,
+/// so tagging does not change what users read.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    inner: anyhow::Error,
+}
+
+impl Error {
+    /// Get the kind of the error
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the top message, the causes follow through source()
+        write!(f, "{}", self.inner)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner.source()
+    }
+}
+
+/// Attach an [`ErrorKind`] to the error of a result
+pub trait ResultExt<T> {
+    fn kind(self, kind: ErrorKind) -> anyhow::Result<T>;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn kind(self, kind: ErrorKind) -> anyhow::Result<T> {
+        self.map_err(|e| {
+            Error {
+                kind,
+                inner: e.into(),
+            }
+            .into()
+        })
+    }
+}
+
+/// All causes of an error, outermost first, including the errors wrapped by tags
+fn causes(error: &anyhow::Error) -> Vec<&(dyn std::error::Error + 'static)> {
+    let mut causes = Vec::new();
+    let mut next: Option<&(dyn std::error::Error + 'static)> = Some(error.as_ref());
+    while let Some(cause) = next {
+        causes.push(cause);
+        next = match cause.downcast_ref::<Error>() {
+            // The tag passes on the source of the wrapped error, not the wrapped error itself
+            Some(tagged) => Some(tagged.inner.as_ref()),
+            None => cause.source(),
+        };
+    }
+    causes
+}
+
+/// Determine the kind of an error
+///
+/// A permission error anywhere in the chain wins, since it is what the user has
+/// to fix. Otherwise the innermost kind is the most specific one: a parse error
+/// inside a failed runner call is reported as a parse error.
+pub fn kind_of(error: &anyhow::Error) -> Option<ErrorKind> {
+    let causes = causes(error);
+    let is_permission_denied = causes.iter().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::PermissionDenied)
+    });
+    if is_permission_denied {
+        return Some(ErrorKind::PermissionDenied);
+    }
+
+    causes.iter().rev().find_map(|cause| {
+        if let Some(tagged) = cause.downcast_ref::<Error>() {
+            Some(tagged.kind())
+        } else if cause.is::<ParseError>() {
+            Some(ErrorKind::Parse)
+        } else if cause.is::<InvariantViolation>() {
+            Some(ErrorKind::Invariant)
+        } else {
+            None
+        }
+    })
+}
+
+/// Exit code for a failed command
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    kind_of(error).map_or(EXIT_FAILURE, ErrorKind::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_tagging_keeps_the_message() {
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("disk on fire"))
+            .context("Failed to save config file")
+            .kind(ErrorKind::State);
+        let err = result.unwrap_err();
+
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to save config file: disk on fire"
+        );
+        assert_eq!(exit_code(&err), ErrorKind::State.exit_code());
+    }
+
+    #[test]
+    fn test_innermost_kind_wins() {
+        let result: anyhow::Result<()> = Err(ParseError::NoCurrent)
+            .context("Failed to parse nix-env --list-generations output")
+            .kind(ErrorKind::Runner)
+            .context("Failed to list generations");
+        assert_eq!(kind_of(&result.unwrap_err()), Some(ErrorKind::Parse));
+    }
+
+    #[test]
+    fn test_nested_tags() {
+        let result: anyhow::Result<()> = Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            .kind(ErrorKind::Runner)
+            .kind(ErrorKind::State);
+        assert_eq!(
+            kind_of(&result.unwrap_err()),
+            Some(ErrorKind::PermissionDenied)
+        );
+
+        let result: anyhow::Result<()> = Err(anyhow::anyhow!("corrupt"))
+            .kind(ErrorKind::State)
+            .kind(ErrorKind::Runner);
+        assert_eq!(kind_of(&result.unwrap_err()), Some(ErrorKind::State));
+    }
+
+    #[test]
+    fn test_permission_denied_wins() {
+        let result: anyhow::Result<()> = Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("Failed to write state")
+            .kind(ErrorKind::State);
+        assert_eq!(exit_code(&result.unwrap_err()), 8);
+    }
+
+    #[test]
+    fn test_untagged_errors() {
+        assert_eq!(exit_code(&anyhow::anyhow!("something else")), EXIT_FAILURE);
+    }
+}
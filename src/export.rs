@@ -0,0 +1,123 @@
+use crate::error::{ErrorKind, ResultExt};
+use crate::fs_util;
+use crate::timestamp;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Format version of export files
+pub const EXPORT_VERSION: u32 = 1;
+
+/// A portable set of protections, written by `export` and read by `import`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportFile {
+    pub version: u32,
+    /// Profile the generations belong to on the exporting machine
+    pub profile: String,
+    /// RFC 3339 UTC timestamp
+    pub exported_at: String,
+    pub protections: Vec<ExportedProtection>,
+}
+
+/// A protected generation in an export file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedProtection {
+    pub generation: u32,
+    /// Store path the generation pointed to, if it could be resolved at export time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_path: Option<PathBuf>,
+    /// Name of the user who protected the generation, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protected_by: Option<String>,
+}
+
+impl ExportFile {
+    /// Create an export of protections for a profile, stamped with the current time
+    pub fn new(profile: &str, protections: Vec<ExportedProtection>) -> Self {
+        Self {
+            version: EXPORT_VERSION,
+            profile: profile.to_string(),
+            exported_at: timestamp::format_rfc3339(timestamp::now()),
+            protections,
+        }
+    }
+
+    /// Serialize the export as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("Failed to serialize export")
+    }
+
+    /// Write the export to a file, atomically replacing it
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs_util::write_atomic(path, self.to_json()?.as_bytes())
+            .with_context(|| format!("Failed to write export file: {}", path.display()))
+            .kind(ErrorKind::State)
+    }
+
+    /// Read an export file
+    /// Files written by newer versions are refused
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read export file: {}", path.display()))
+            .kind(ErrorKind::State)?;
+        let export: Self = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse export file: {}", path.display()))
+            .kind(ErrorKind::State)?;
+
+        if export.version > EXPORT_VERSION {
+            return Err(anyhow::anyhow!(
+                "{} was exported by a newer version of lock-generations (format {}, this \
+                 version reads up to {}). Please upgrade lock-generations.",
+                path.display(),
+                export.version,
+                EXPORT_VERSION
+            ))
+            .kind(ErrorKind::State);
+        }
+        Ok(export)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_and_read() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("export.json");
+        let export = ExportFile::new(
+            "/nix/var/nix/profiles/system",
+            vec![
+                ExportedProtection {
+                    generation: 42,
+                    store_path: Some(PathBuf::from("/nix/store/abc-nixos-system")),
+                    protected_by: Some("alice".to_string()),
+                },
+                ExportedProtection {
+                    generation: 43,
+                    store_path: None,
+                    protected_by: None,
+                },
+            ],
+        );
+
+        export.write(&path).unwrap();
+        assert_eq!(ExportFile::read(&path).unwrap(), export);
+    }
+
+    #[test]
+    fn test_newer_version_is_refused() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("export.json");
+        let mut export = ExportFile::new("/nix/var/nix/profiles/system", vec![]);
+        export.version = EXPORT_VERSION + 1;
+        export.write(&path).unwrap();
+
+        let err = ExportFile::read(&path).unwrap_err();
+        assert!(err.to_string().contains("Please upgrade lock-generations"));
+        assert_eq!(crate::error::kind_of(&err), Some(ErrorKind::State));
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{PermissionsExt, chown};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Owner to hand created files and directories to, e.g. the invoking user under sudo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Ownership and permissions applied to created files and directories
+/// The default leaves both to the process (its user and umask)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAccess {
+    pub owner: Option<Owner>,
+    /// Mode bits for created files, e.g. 0o664
+    pub file_mode: Option<u32>,
+    /// Mode bits for created directories, e.g. 0o2775
+    pub dir_mode: Option<u32>,
+}
+
+/// Counter making temp file names unique within this process
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Atomically replace `path` with `contents`
+///
+/// The contents are written to a uniquely named temp file in the same directory,
+/// flushed to disk and renamed over `path`. The directory is synced afterwards so
+/// the rename itself survives a crash.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    write_atomic_as(path, contents, FileAccess::default())
+}
+
+/// Like `write_atomic`, but applies `access` to the file and any directories it creates
+pub fn write_atomic_as(path: &Path, contents: &[u8], access: FileAccess) -> Result<()> {
+    let parent = parent_dir(path);
+    create_dir_all_as(parent, access)?;
+
+    let tmp_path = tmp_path_for(path);
+    let result = (|| -> Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .with_context(|| format!("Failed to create temp file: {}", tmp_path.display()))?;
+        file.write_all(contents)
+            .with_context(|| format!("Failed to write temp file: {}", tmp_path.display()))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to sync temp file: {}", tmp_path.display()))?;
+        // Apply access before the rename, so the file never appears with the wrong owner
+        apply_file_access(&tmp_path, access)?;
+
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to save file: {}", path.display()))
+    })();
+
+    if result.is_err() {
+        // Best effort, the original error is what matters
+        let _ = fs::remove_file(&tmp_path);
+        return result;
+    }
+
+    sync_dir(parent)
+}
+
+/// Create a directory and its missing parents, applying `access` to the created ones
+/// Directories that already exist keep their ownership and permissions
+pub fn create_dir_all_as(dir: &Path, access: FileAccess) -> Result<()> {
+    let mut missing = Vec::new();
+    let mut current = Some(dir);
+    while let Some(path) = current
+        && !path.as_os_str().is_empty()
+        && !path.exists()
+    {
+        missing.push(path);
+        current = path.parent();
+    }
+
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+
+    for path in missing.iter().rev() {
+        if let Some(owner) = access.owner {
+            chown_to(path, owner)?;
+        }
+        if let Some(mode) = access.dir_mode {
+            set_mode(path, mode)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply the file part of `access` to a file
+pub fn apply_file_access(path: &Path, access: FileAccess) -> Result<()> {
+    if let Some(owner) = access.owner {
+        chown_to(path, owner)?;
+    }
+    if let Some(mode) = access.file_mode {
+        set_mode(path, mode)?;
+    }
+    Ok(())
+}
+
+/// Change the owner of a file or directory
+fn chown_to(path: &Path, owner: Owner) -> Result<()> {
+    chown(path, Some(owner.uid), Some(owner.gid))
+        .with_context(|| format!("Failed to change owner of {}", path.display()))
+}
+
+/// Change the mode bits of a file or directory, bypassing the umask
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to change permissions of {}", path.display()))
+}
+
+/// Flush a directory's entries to disk
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .with_context(|| format!("Failed to sync directory: {}", dir.display()))
+}
+
+/// Directory containing `path`, treating a bare file name as relative to the current directory
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Unique temp file path next to `path`, e.g. .protected.json.1234.0.tmp
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let counter = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    parent_dir(path).join(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        counter
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("nested").join("file.json");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        // No temp files are left behind
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_atomic_as_hands_over_created_paths() {
+        use std::os::unix::fs::MetadataExt;
+
+        // Changing ownership needs root
+        if !crate::paths::is_root() {
+            return;
+        }
+
+        let tmp_dir = TempDir::new().unwrap();
+        let access = FileAccess {
+            owner: Some(Owner {
+                uid: 4242,
+                gid: 4242,
+            }),
+            ..Default::default()
+        };
+        let path = tmp_dir.path().join("a").join("b").join("file.json");
+
+        write_atomic_as(&path, b"{}", access).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().uid(), 4242);
+        assert_eq!(fs::metadata(tmp_dir.path().join("a")).unwrap().uid(), 4242);
+        assert_eq!(fs::metadata(path.parent().unwrap()).unwrap().gid(), 4242);
+        // Pre-existing directories keep their owner
+        assert_eq!(fs::metadata(tmp_dir.path()).unwrap().uid(), 0);
+    }
+
+    #[test]
+    fn test_write_atomic_as_applies_modes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let access = FileAccess {
+            file_mode: Some(0o664),
+            dir_mode: Some(0o2775),
+            ..Default::default()
+        };
+        let path = tmp_dir.path().join("shared").join("state.json");
+
+        write_atomic_as(&path, b"{}", access).unwrap();
+
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&path), 0o664);
+        assert_eq!(mode(path.parent().unwrap()), 0o2775);
+    }
+
+    #[test]
+    fn test_tmp_paths_are_unique() {
+        let path = Path::new("/tmp/protected.json");
+        assert_ne!(tmp_path_for(path), tmp_path_for(path));
+    }
+}
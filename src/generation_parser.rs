@@ -0,0 +1,548 @@
+use std::fmt;
+
+/// The marker nix-env appends to the line of the active generation
+const CURRENT_MARKER: &str = "(current)";
+
+/// How strictly `nix-env --list-generations` output is checked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Skip lines that do not start with a generation number, reporting them as skipped
+    #[default]
+    Lenient,
+    /// Reject any line that is not exactly `<number> <YYYY-MM-DD> <HH:MM:SS> [(current)]`
+    Strict,
+}
+
+/// Generations read from one `nix-env --list-generations` output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    /// Generation numbers in the order they were listed
+    pub generations: Vec<u32>,
+    pub current: u32,
+    /// Lines ignored in lenient mode
+    pub skipped: Vec<SkippedLine>,
+}
+
+/// A line of output that did not describe a generation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedLine {
+    /// 1-based line number in the output
+    pub line_number: usize,
+    pub line: String,
+}
+
+/// Why `nix-env --list-generations` output could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A line does not describe a generation (strict mode only)
+    UnexpectedLine { line_number: usize, line: String },
+    /// A line starts like a generation, but its number is not a valid u32
+    InvalidGenerationNumber { line_number: usize, token: String },
+    /// The date and time of a generation are not `YYYY-MM-DD HH:MM:SS` (strict mode only)
+    InvalidTimestamp { line_number: usize, line: String },
+    /// The same generation is listed twice
+    DuplicateGeneration { line_number: usize, generation: u32 },
+    /// More than one generation is marked as current
+    MultipleCurrent { first: u32, second: u32 },
+    /// No generation is marked as current
+    NoCurrent,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedLine { line_number, line } => {
+                write!(f, "unexpected line {}: {:?}", line_number, line)
+            }
+            ParseError::InvalidGenerationNumber { line_number, token } => {
+                write!(
+                    f,
+                    "invalid generation number {:?} on line {}",
+                    token, line_number
+                )
+            }
+            ParseError::InvalidTimestamp { line_number, line } => {
+                write!(f, "invalid timestamp on line {}: {:?}", line_number, line)
+            }
+            ParseError::DuplicateGeneration {
+                line_number,
+                generation,
+            } => write!(
+                f,
+                "generation {} is listed again on line {}",
+                generation, line_number
+            ),
+            ParseError::MultipleCurrent { first, second } => write!(
+                f,
+                "both generation {} and {} are marked as current",
+                first, second
+            ),
+            ParseError::NoCurrent => write!(f, "no generation is marked as current"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse the output of `nix-env --list-generations`
+///
+/// Each generation is printed as its number, creation date and time, and
+/// `(current)` for the active one:
+///
+/// ```text
+///    1   2024-01-15 10:30:45
+///    2   2024-01-16 14:20:10   (current)
+/// ```
+///
+/// Blank lines, surrounding whitespace and CRLF line endings are accepted in
+/// both modes. A line whose first token starts with a digit but is not a valid
+/// generation number is always an error, so a format change can never make a
+/// generation silently disappear from the listing.
+pub fn parse_generations(output: &str, mode: ParseMode) -> Result<Listing, ParseError> {
+    let mut generations: Vec<u32> = Vec::new();
+    let mut current = None;
+    let mut skipped = Vec::new();
+
+    for (index, raw_line) in output.lines().enumerate() {
+        let line_number = index + 1;
+        let line = raw_line.trim();
+        let mut tokens = line.split_whitespace();
+        let Some(first) = tokens.next() else {
+            continue;
+        };
+
+        if !first.starts_with(|c: char| c.is_ascii_digit()) {
+            match mode {
+                ParseMode::Lenient => {
+                    skipped.push(SkippedLine {
+                        line_number,
+                        line: line.to_string(),
+                    });
+                    continue;
+                }
+                ParseMode::Strict => {
+                    return Err(ParseError::UnexpectedLine {
+                        line_number,
+                        line: line.to_string(),
+                    });
+                }
+            }
+        }
+
+        let number: u32 = first
+            .parse()
+            .map_err(|_| ParseError::InvalidGenerationNumber {
+                line_number,
+                token: first.to_string(),
+            })?;
+
+        let rest: Vec<&str> = tokens.collect();
+        let is_current = rest.last() == Some(&CURRENT_MARKER);
+        if mode == ParseMode::Strict {
+            let timestamp = if is_current {
+                &rest[..rest.len() - 1]
+            } else {
+                &rest[..]
+            };
+            if !matches!(timestamp, [date, time] if is_date(date) && is_time(time)) {
+                return Err(ParseError::InvalidTimestamp {
+                    line_number,
+                    line: line.to_string(),
+                });
+            }
+        }
+
+        if generations.contains(&number) {
+            return Err(ParseError::DuplicateGeneration {
+                line_number,
+                generation: number,
+            });
+        }
+        generations.push(number);
+
+        if is_current {
+            if let Some(first) = current {
+                return Err(ParseError::MultipleCurrent {
+                    first,
+                    second: number,
+                });
+            }
+            current = Some(number);
+        }
+    }
+
+    let current = current.ok_or(ParseError::NoCurrent)?;
+    Ok(Listing {
+        generations,
+        current,
+        skipped,
+    })
+}
+
+/// Parse the output of `nix profile history`, returning the listed version numbers
+///
+/// Each version starts with an unindented header, followed by indented lines
+/// describing the changed packages:
+///
+/// ```text
+/// Version 1 (2024-01-15):
+///   flake:nixpkgs#hello: ∅ -> 2.12.1
+///
+/// Version 2 (2024-01-16) <- 1:
+///   flake:nixpkgs#hello: 2.12.1 -> ∅
+/// ```
+///
+/// The current version is only highlighted with color, so it has to be taken
+/// from the profile link instead. Color codes are stripped before parsing. In
+/// strict mode every unindented line must be a `Version N (YYYY-MM-DD)` header.
+pub fn parse_profile_history(output: &str, mode: ParseMode) -> Result<Vec<u32>, ParseError> {
+    let mut versions: Vec<u32> = Vec::new();
+
+    for (index, raw_line) in strip_ansi(output).lines().enumerate() {
+        let line_number = index + 1;
+        // Package lines are indented, and blank lines separate the versions
+        if raw_line.trim().is_empty() || raw_line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let line = raw_line.trim();
+
+        let mut tokens = line.split_whitespace();
+        let number = match (tokens.next(), tokens.next()) {
+            (Some("Version"), Some(token)) if token.starts_with(|c: char| c.is_ascii_digit()) => {
+                // The number is followed by the date directly on older Nix versions
+                let token = token.trim_end_matches(':');
+                token
+                    .parse::<u32>()
+                    .map_err(|_| ParseError::InvalidGenerationNumber {
+                        line_number,
+                        token: token.to_string(),
+                    })?
+            }
+            _ if mode == ParseMode::Lenient => continue,
+            _ => {
+                return Err(ParseError::UnexpectedLine {
+                    line_number,
+                    line: line.to_string(),
+                });
+            }
+        };
+
+        if mode == ParseMode::Strict {
+            let date = tokens
+                .next()
+                .and_then(|t| t.trim_end_matches(':').strip_prefix('('));
+            if !date.is_some_and(|d| d.strip_suffix(')').is_some_and(is_date)) {
+                return Err(ParseError::InvalidTimestamp {
+                    line_number,
+                    line: line.to_string(),
+                });
+            }
+        }
+
+        if versions.contains(&number) {
+            return Err(ParseError::DuplicateGeneration {
+                line_number,
+                generation: number,
+            });
+        }
+        versions.push(number);
+    }
+
+    Ok(versions)
+}
+
+/// Remove ANSI color and style escape sequences
+fn strip_ansi(output: &str) -> String {
+    let mut stripped = String::with_capacity(output.len());
+    let mut chars = output.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip `ESC [ parameters final-letter`
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Check that all characters are ASCII digits, with `separator` at the given positions
+fn matches_pattern(value: &str, len: usize, separator: u8, positions: &[usize]) -> bool {
+    value.len() == len
+        && value.bytes().enumerate().all(|(i, b)| {
+            if positions.contains(&i) {
+                b == separator
+            } else {
+                b.is_ascii_digit()
+            }
+        })
+}
+
+/// YYYY-MM-DD
+fn is_date(value: &str) -> bool {
+    matches_pattern(value, 10, b'-', &[4, 7])
+}
+
+/// HH:MM:SS
+fn is_time(value: &str) -> bool {
+    matches_pattern(value, 8, b':', &[2, 5])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{TestResult, quickcheck};
+    use std::collections::BTreeSet;
+
+    /// Captured `nix-env --list-generations` outputs
+    const FIXTURES: &[(&str, &str)] = &[
+        (
+            "system",
+            include_str!("../tests/fixtures/list-generations/system.txt"),
+        ),
+        (
+            "single",
+            include_str!("../tests/fixtures/list-generations/single.txt"),
+        ),
+        (
+            "rolled-back",
+            include_str!("../tests/fixtures/list-generations/rolled-back.txt"),
+        ),
+        (
+            "wide-numbers",
+            include_str!("../tests/fixtures/list-generations/wide-numbers.txt"),
+        ),
+    ];
+
+    /// Captured `nix profile history` outputs
+    const HISTORY_FIXTURES: &[(&str, &str, &[u32])] = &[
+        (
+            "user-profile",
+            include_str!("../tests/fixtures/profile-history/user-profile.txt"),
+            &[1, 2, 3],
+        ),
+        (
+            "colored",
+            include_str!("../tests/fixtures/profile-history/colored.txt"),
+            &[4, 5, 7],
+        ),
+    ];
+
+    /// Render generations the way nix-env prints them
+    fn render(generations: &[u32], current: u32) -> String {
+        generations
+            .iter()
+            .map(|&number| {
+                let marker = if number == current {
+                    CURRENT_MARKER
+                } else {
+                    ""
+                };
+                format!("{:4}   2024-01-15 10:30:45   {}\n", number, marker)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fixtures_parse_in_both_modes() {
+        for (name, output) in FIXTURES {
+            let strict = parse_generations(output, ParseMode::Strict)
+                .unwrap_or_else(|e| panic!("fixture {}: {}", name, e));
+            let lenient = parse_generations(output, ParseMode::Lenient).unwrap();
+            assert_eq!(strict, lenient, "fixture {}", name);
+            assert!(strict.skipped.is_empty(), "fixture {}", name);
+        }
+    }
+
+    #[test]
+    fn test_system_fixture() {
+        let listing = parse_generations(FIXTURES[0].1, ParseMode::Strict).unwrap();
+        assert_eq!(listing.generations, vec![1, 2, 3, 4, 5]);
+        assert_eq!(listing.current, 5);
+    }
+
+    #[test]
+    fn test_rolled_back_fixture() {
+        let listing = parse_generations(FIXTURES[2].1, ParseMode::Strict).unwrap();
+        assert_eq!(listing.generations, vec![118, 119, 120, 121]);
+        assert_eq!(listing.current, 119);
+    }
+
+    #[test]
+    fn test_unexpected_lines() {
+        let output = "warning: something odd\n   1   2024-01-15 10:30:45   (current)\n";
+
+        let listing = parse_generations(output, ParseMode::Lenient).unwrap();
+        assert_eq!(listing.generations, vec![1]);
+        assert_eq!(
+            listing.skipped,
+            vec![SkippedLine {
+                line_number: 1,
+                line: "warning: something odd".to_string()
+            }]
+        );
+
+        assert_eq!(
+            parse_generations(output, ParseMode::Strict),
+            Err(ParseError::UnexpectedLine {
+                line_number: 1,
+                line: "warning: something odd".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_number_is_an_error_in_both_modes() {
+        for mode in [ParseMode::Lenient, ParseMode::Strict] {
+            assert_eq!(
+                parse_generations(
+                    "   1   2024-01-15 10:30:45\n  99999999999   x   (current)\n",
+                    mode
+                ),
+                Err(ParseError::InvalidGenerationNumber {
+                    line_number: 2,
+                    token: "99999999999".to_string()
+                })
+            );
+            assert!(matches!(
+                parse_generations("  2a   2024-01-15 10:30:45   (current)\n", mode),
+                Err(ParseError::InvalidGenerationNumber { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_timestamp_format_is_only_checked_in_strict_mode() {
+        let output = "   7   15.01.2024 10:30   (current)\r\n";
+        assert_eq!(
+            parse_generations(output, ParseMode::Lenient)
+                .unwrap()
+                .generations,
+            vec![7]
+        );
+        assert!(matches!(
+            parse_generations(output, ParseMode::Strict),
+            Err(ParseError::InvalidTimestamp { line_number: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_inconsistent_listings() {
+        assert_eq!(
+            parse_generations("", ParseMode::Lenient),
+            Err(ParseError::NoCurrent)
+        );
+        assert_eq!(
+            parse_generations(&render(&[1, 2], 3), ParseMode::Lenient),
+            Err(ParseError::NoCurrent)
+        );
+        assert_eq!(
+            parse_generations(
+                &format!("{}{}", render(&[1], 1), render(&[1], 0)),
+                ParseMode::Lenient
+            ),
+            Err(ParseError::DuplicateGeneration {
+                line_number: 2,
+                generation: 1
+            })
+        );
+        assert_eq!(
+            parse_generations(
+                &format!("{}{}", render(&[1], 1), render(&[2], 2)),
+                ParseMode::Lenient
+            ),
+            Err(ParseError::MultipleCurrent {
+                first: 1,
+                second: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_profile_history_fixtures() {
+        for (name, output, versions) in HISTORY_FIXTURES {
+            for mode in [ParseMode::Lenient, ParseMode::Strict] {
+                assert_eq!(
+                    parse_profile_history(output, mode).as_deref(),
+                    Ok(*versions),
+                    "fixture {}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_profile_history_unexpected_lines() {
+        let output =
+            "Version 1 (2024-01-15):\n  flake:nixpkgs#hello: ∅ -> 2.12.1\nsomething else\n";
+        assert_eq!(
+            parse_profile_history(output, ParseMode::Lenient),
+            Ok(vec![1])
+        );
+        assert!(matches!(
+            parse_profile_history(output, ParseMode::Strict),
+            Err(ParseError::UnexpectedLine { line_number: 3, .. })
+        ));
+        assert!(matches!(
+            parse_profile_history("Version 1x (2024-01-15):\n", ParseMode::Lenient),
+            Err(ParseError::InvalidGenerationNumber { .. })
+        ));
+    }
+
+    quickcheck! {
+        /// Whatever nix-env prints for a set of generations parses back to exactly them
+        fn prop_rendered_listing_roundtrips(numbers: BTreeSet<u32>, pick: usize) -> TestResult {
+            if numbers.is_empty() {
+                return TestResult::discard();
+            }
+            let generations: Vec<u32> = numbers.into_iter().collect();
+            let current = generations[pick % generations.len()];
+            let output = render(&generations, current);
+
+            let expected = Listing { generations, current, skipped: Vec::new() };
+            TestResult::from_bool(
+                parse_generations(&output, ParseMode::Strict) == Ok(expected.clone())
+                    && parse_generations(&output, ParseMode::Lenient) == Ok(expected),
+            )
+        }
+
+        /// Arbitrary input never panics, and whatever strict mode accepts, lenient mode reads the same
+        fn prop_arbitrary_output_never_panics(output: String) -> bool {
+            let lenient = parse_generations(&output, ParseMode::Lenient);
+            match parse_generations(&output, ParseMode::Strict) {
+                Ok(strict) => lenient == Ok(strict),
+                Err(_) => true,
+            }
+        }
+
+        /// Arbitrary history output never panics
+        fn prop_arbitrary_history_never_panics(output: String) -> bool {
+            let lenient = parse_profile_history(&output, ParseMode::Lenient);
+            match parse_profile_history(&output, ParseMode::Strict) {
+                Ok(strict) => lenient == Ok(strict),
+                Err(_) => true,
+            }
+        }
+
+        /// Every listed generation comes from the first token of a line, nothing is made up
+        fn prop_lenient_generations_come_from_lines(lines: Vec<String>) -> bool {
+            let output = lines.join("\n");
+            match parse_generations(&output, ParseMode::Lenient) {
+                Ok(listing) => listing.generations.iter().all(|number| {
+                    output
+                        .lines()
+                        .filter_map(|line| line.split_whitespace().next()?.parse::<u32>().ok())
+                        .any(|first| first == *number)
+                }),
+                Err(_) => true,
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::command_runner::GenerationSnapshot;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// A postcondition of `clean` that does not hold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A protected generation is gone
+    ProtectedMissing(u32),
+    /// The generation that was current is gone
+    CurrentMissing(u32),
+    /// The generation the system was booted from is gone
+    BootedMissing(u32),
+    /// A generation planned for deletion is still present, although deleting it reported success
+    NotDeleted(u32),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::ProtectedMissing(g) => write!(f, "protected generation {} is gone", g),
+            Violation::CurrentMissing(g) => write!(f, "current generation {} is gone", g),
+            Violation::BootedMissing(g) => write!(f, "booted generation {} is gone", g),
+            Violation::NotDeleted(g) => write!(
+                f,
+                "generation {} is still present although deleting it succeeded",
+                g
+            ),
+        }
+    }
+}
+
+/// The generations of a profile after `clean` are not what the plan promised
+///
+/// This points at a bug in a runner or at something else changing the profile
+/// concurrently, so it is reported separately from ordinary deletion failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvariantViolation {
+    pub profile: String,
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let violations: Vec<String> = self.violations.iter().map(|v| v.to_string()).collect();
+        write!(
+            f,
+            "Clean left {} in an unexpected state: {}",
+            self.profile,
+            violations.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// Check the postconditions of a clean
+///
+/// Every protected, current and booted generation of `before` must still be
+/// present in `after`, and every generation in `planned` must be gone unless
+/// deleting it reported an error (listed in `failed`).
+pub fn check_clean(
+    profile: &str,
+    before: &GenerationSnapshot,
+    after: &GenerationSnapshot,
+    protected: &BTreeSet<u32>,
+    planned: &[u32],
+    failed: &BTreeMap<u32, String>,
+) -> Result<(), InvariantViolation> {
+    let existed: BTreeSet<u32> = before.generations.iter().map(|g| g.number).collect();
+    let present: BTreeSet<u32> = after.generations.iter().map(|g| g.number).collect();
+    let mut violations = Vec::new();
+
+    if !present.contains(&before.current) {
+        violations.push(Violation::CurrentMissing(before.current));
+    }
+    if let Some(booted) = before.booted
+        && !present.contains(&booted)
+    {
+        violations.push(Violation::BootedMissing(booted));
+    }
+    for &generation in protected.intersection(&existed) {
+        if !present.contains(&generation) {
+            violations.push(Violation::ProtectedMissing(generation));
+        }
+    }
+    for &generation in planned {
+        if present.contains(&generation) && !failed.contains_key(&generation) {
+            violations.push(Violation::NotDeleted(generation));
+        }
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(InvariantViolation {
+            profile: profile.to_string(),
+            violations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_runner::Generation;
+
+    fn snapshot(numbers: &[u32], current: u32, booted: Option<u32>) -> GenerationSnapshot {
+        GenerationSnapshot {
+            generations: numbers
+                .iter()
+                .map(|&number| Generation {
+                    number,
+                    created: None,
+                })
+                .collect(),
+            current,
+            booted,
+        }
+    }
+
+    #[test]
+    fn test_clean_as_planned() {
+        let before = snapshot(&[1, 2, 3, 4, 5], 5, Some(4));
+        let after = snapshot(&[2, 4, 5], 5, Some(4));
+        let protected = BTreeSet::from([2]);
+        assert_eq!(
+            check_clean("p", &before, &after, &protected, &[1, 3], &BTreeMap::new()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        let before = snapshot(&[1, 2, 3, 4, 5], 5, Some(4));
+        let after = snapshot(&[1, 3], 3, None);
+        let protected = BTreeSet::from([2, 9]);
+        // Deleting 3 failed, so it being present is an ordinary failure
+        let failed = BTreeMap::from([(3, "busy".to_string())]);
+
+        let err = check_clean("p", &before, &after, &protected, &[1, 3], &failed).unwrap_err();
+        assert_eq!(
+            err.violations,
+            vec![
+                Violation::CurrentMissing(5),
+                Violation::BootedMissing(4),
+                Violation::ProtectedMissing(2),
+                Violation::NotDeleted(1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Selective protection and cleanup of Nix profile generations
+//!
+//! The `lock-generations` binary is a thin command-line layer over this crate.
+//! Generations are listed and deleted through a [`NixOsCommandRunner`]; pick one
+//! for a profile with [`commands::runner_for_profile`]. [`RetentionPolicy::plan`]
+//! decides which generations a clean keeps, given the protections of a
+//! [`ProtectedState`], and [`commands::clean_generations`] carries the plan out.
+//!
+//! ```
+//! use lock_generations::{Generation, ProtectedState, RetentionPolicy};
+//!
+//! let generations: Vec<Generation> = (1..=5)
+//!     .map(|number| Generation { number, created: None })
+//!     .collect();
+//! let mut state = ProtectedState::new();
+//! state.protect(2);
+//! let policy = RetentionPolicy {
+//!     keep_last: Some(1),
+//!     ..Default::default()
+//! };
+//!
+//! let plan = policy.plan(&generations, 4, None, &state.all_protected(), 0);
+//! assert_eq!(plan.delete, vec![1, 3]);
+//! ```
+
+pub mod audit;
+pub mod command_runner;
+#[cfg(feature = "nix-env")]
+pub mod commands;
+pub mod config;
+pub mod error;
+pub mod export;
+mod fs_util;
+pub mod generation_parser;
+pub mod invariants;
+mod migrations;
+#[cfg(all(test, feature = "nix-env"))]
+mod mock_runner;
+pub mod nix_command;
+#[cfg(feature = "nix-profile")]
+pub mod nix_profile_runner;
+pub mod paths;
+pub mod protected_state;
+#[cfg(feature = "nix-env")]
+pub mod real_runner;
+pub mod recovery;
+pub mod retention;
+pub mod state_history;
+pub mod timestamp;
+
+pub use command_runner::{Generation, GenerationSnapshot, NixOsCommandRunner};
+#[cfg(feature = "nix-profile")]
+pub use nix_profile_runner::NixProfileRunner;
+pub use protected_state::ProtectedState;
+#[cfg(feature = "nix-env")]
+pub use real_runner::RealNixOsRunner;
+pub use retention::{Plan, RetentionPolicy};
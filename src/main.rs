@@ -1,20 +1,71 @@
-mod command_runner;
-#[cfg(test)]
-mod mock_runner;
-mod protected_state;
-mod real_runner;
-
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use command_runner::NixOsCommandRunner;
-use protected_state::ProtectedState;
-use real_runner::RealNixOsRunner;
-use std::collections::HashSet;
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use lock_generations::audit::{AuditAction, AuditLog};
+use lock_generations::commands::{self, CleanOptions, ImportOptions};
+use lock_generations::config::{CleanConfig, Config, NixConfig, OutputFormat};
+use lock_generations::error;
+use lock_generations::generation_parser::ParseMode;
+use lock_generations::paths;
+use lock_generations::protected_state::{StateFile, StateMode};
+use lock_generations::recovery::RecoveryLog;
+use lock_generations::retention::{Age, RetentionPolicy};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[command(name = "lock-generations")]
 #[command(about = "Manage NixOS system generations with selective protection", long_about = None)]
 struct Cli {
+    /// Where protections are kept: per-user files or a state file shared by all admins [default: user]
+    #[arg(long, global = true, value_enum, env = "LOCK_GENERATIONS_MODE")]
+    mode: Option<StateMode>,
+
+    /// Use this state file instead of the default one of the mode
+    #[arg(long, global = true, env = "LOCK_GENERATIONS_STATE")]
+    state_file: Option<PathBuf>,
+
+    /// nix-env binary to run instead of the one in PATH
+    #[arg(long, global = true, value_name = "PATH")]
+    nix_env_bin: Option<PathBuf>,
+
+    /// nix binary to run instead of the one in PATH, for `nix profile` profiles
+    #[arg(long, global = true, value_name = "PATH")]
+    nix_bin: Option<PathBuf>,
+
+    /// Extra argument for every nix-env and nix invocation, e.g. --nix-arg=--store --nix-arg=/mnt
+    #[arg(
+        long = "nix-arg",
+        global = true,
+        value_name = "ARG",
+        allow_hyphen_values = true
+    )]
+    nix_args: Vec<String>,
+
+    /// Environment variable for every nix-env and nix invocation
+    #[arg(long = "nix-setenv", global = true, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    nix_env_vars: Vec<(String, String)>,
+
+    /// Print each Nix command line before running it
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Fail on any unexpected line of nix-env output instead of skipping it, e.g. in CI
+    #[arg(
+        long,
+        global = true,
+        env = "LOCK_GENERATIONS_STRICT",
+        value_parser = clap::builder::BoolishValueParser::new()
+    )]
+    strict: bool,
+
+    /// Profile whose generations are managed [default: /nix/var/nix/profiles/system]
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// How results are printed [default: text]
+    #[arg(long, global = true, value_enum)]
+    output: Option<OutputFormat>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,6 +76,12 @@ enum Commands {
     Protect {
         /// Generation number to protect
         generation: u32,
+        /// Also register the generation's store path as a Nix GC root
+        #[arg(long)]
+        gc_root: bool,
+        /// Also copy the generation into its own system-profiles/keep-<gen> profile
+        #[arg(long)]
+        pin_profile: bool,
     },
     /// Remove protection from a generation
     Unprotect {
@@ -32,400 +89,325 @@ enum Commands {
         generation: u32,
     },
     /// Remove all unprotected generations (except current)
+    ///
+    /// Options not given fall back to the [clean] section of config.toml
     Clean {
-        /// Keep the last N most recent generations
-        #[arg(long)]
-        keep_last: Option<usize>,
+        #[command(flatten)]
+        retention: RetentionArgs,
         /// Show what would be done without actually deleting
         #[arg(long)]
         dry_run: bool,
+        /// Keep the closures of deleted generations alive with temporary GC roots,
+        /// so they can be restored until purge-quarantine releases them
+        #[arg(long, overrides_with = "no_quarantine")]
+        quarantine: bool,
+        /// Do not quarantine, even if config.toml says so
+        #[arg(long)]
+        no_quarantine: bool,
+        /// Number of days quarantined generations are kept [default: 7]
+        #[arg(long)]
+        grace_days: Option<u64>,
+        /// Wait for a running nixos-rebuild or garbage collection to finish
+        /// instead of refusing to clean
+        #[arg(long)]
+        wait: bool,
+        /// Keep deleting the remaining generations when deleting some of them fails
+        #[arg(long)]
+        keep_going: bool,
     },
     /// List all protected generations
     List,
+    /// Make the GC roots match the protected generations
+    SyncRoots {
+        /// Show what would be changed without touching any roots
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Recreate a deleted generation, as long as it has not been garbage collected
+    Restore {
+        /// Generation number to restore; lists restorable generations if omitted
+        generation: Option<u32>,
+    },
+    /// Release quarantined generations whose grace period has passed
+    ///
+    /// Removes their GC roots and forgets them, so they can no longer be restored.
+    /// Their closures are deleted by the next garbage collection, e.g. nix-collect-garbage
+    PurgeQuarantine {
+        /// Release all quarantined generations, regardless of their grace period
+        #[arg(long)]
+        all: bool,
+        /// Show what would be released without touching any roots
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Show the audit log of protect, unprotect, clean and other actions
+    History {
+        /// Only show entries involving this generation
+        #[arg(long)]
+        generation: Option<u32>,
+        /// Only show entries for this action
+        #[arg(long, value_enum)]
+        action: Option<AuditAction>,
+        /// Only show the N most recent matching entries
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Write the protections, with their store paths, to a portable file
+    Export {
+        /// File to write; prints to stdout if omitted
+        file: Option<PathBuf>,
+    },
+    /// Read protections from a file written by export
+    ///
+    /// Generations that are missing locally or point to another store path than in the
+    /// export are reported and skipped
+    Import {
+        /// File written by export
+        file: PathBuf,
+        /// Replace the current protections instead of merging with them
+        #[arg(long)]
+        replace: bool,
+        /// Also import generations whose store path does not match
+        #[arg(long)]
+        force: bool,
+        /// Show what would be imported without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Revert the most recent change to the protections
+    Undo,
+    /// Show the recent changes to the protections that undo can revert
+    StateHistory {
+        /// Only show the N most recent changes
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Show which generations clean would keep or delete, and why
+    Explain {
+        #[command(flatten)]
+        retention: RetentionArgs,
+    },
+    /// Inspect the settings from config.toml
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let runner = RealNixOsRunner::new();
-
-    match cli.command {
-        Commands::Protect { generation } => protect_generation(generation),
-        Commands::Unprotect { generation } => unprotect_generation(generation),
-        Commands::Clean { keep_last, dry_run } => clean_generations(&runner, keep_last, dry_run),
-        Commands::List => list_protected(),
-    }
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Print the effective settings, merged from all config files and flags
+    Show,
 }
 
-/// Add protection to a specific generation to prevent it from being deleted
-///
-/// This function loads the current protection state, adds the specified generation
-/// to the protected list, and saves the updated state. If the generation is already
-/// protected, it informs the user without making changes.
-///
-/// # Arguments
-///
-/// * `generation` - The generation number to protect
-///
-/// # Returns
-///
-/// Returns `Ok(())` on success, or an error if the state cannot be loaded or saved
-fn protect_generation(generation: u32) -> Result<()> {
-    let mut state = ProtectedState::load()?;
-
-    if state.protect(generation) {
-        state.save()?;
-        println!("Protected generation {}", generation);
-    } else {
-        println!("Generation {} is already protected", generation);
-    }
-
-    Ok(())
+/// Retention options shared by `clean` and `explain`
+#[derive(Args)]
+struct RetentionArgs {
+    /// Use the retention rules of a [policy.<name>] preset from config.toml
+    #[arg(long)]
+    policy: Option<String>,
+    /// Keep the last N most recent generations
+    #[arg(long)]
+    keep_last: Option<usize>,
+    /// Only delete generations older than this, e.g. 14d, 2w or 12h
+    #[arg(long)]
+    older_than: Option<Age>,
+    /// Keep the newest generation of each of the last N days
+    #[arg(long)]
+    keep_daily: Option<usize>,
+    /// Keep the newest generation of each of the last N weeks
+    #[arg(long)]
+    keep_weekly: Option<usize>,
+    /// Keep the newest generation of each of the last N months
+    #[arg(long)]
+    keep_monthly: Option<usize>,
 }
 
-/// Remove protection from a specific generation, allowing it to be deleted
-///
-/// This function loads the current protection state, removes the specified generation
-/// from the protected list, and saves the updated state. If the generation was not
-/// protected, it informs the user without making changes.
-///
-/// # Arguments
-///
-/// * `generation` - The generation number to unprotect
-///
-/// # Returns
-///
-/// Returns `Ok(())` on success, or an error if the state cannot be loaded or saved
-fn unprotect_generation(generation: u32) -> Result<()> {
-    let mut state = ProtectedState::load()?;
-
-    if state.unprotect(generation) {
-        state.save()?;
-        println!("Unprotected generation {}", generation);
-    } else {
-        println!("Generation {} was not protected", generation);
+impl RetentionArgs {
+    /// Resolve the retention policy: the preset or [clean] rules, overridden by these flags
+    fn resolve(self, config: &Config) -> Result<RetentionPolicy> {
+        Ok(config
+            .retention_policy(self.policy.as_deref())?
+            .merge(RetentionPolicy {
+                name: None,
+                keep_last: self.keep_last,
+                older_than: self.older_than,
+                keep_daily: self.keep_daily,
+                keep_weekly: self.keep_weekly,
+                keep_monthly: self.keep_monthly,
+                ..Default::default()
+            }))
     }
-
-    Ok(())
 }
 
-/// Clean up old NixOS generations while preserving protected and recent ones
-///
-/// This function determines which generations should be deleted based on the following rules:
-/// - The current active generation is always preserved
-/// - All explicitly protected generations are preserved
-/// - If `keep_last` is specified, the N most recent generations are preserved
-/// - All other generations are deleted
-///
-/// # Arguments
-///
-/// * `runner` - The command runner to use for querying and deleting generations
-/// * `keep_last` - Optional number of most recent generations to preserve
-/// * `dry_run` - If true, shows what would be deleted without actually deleting
-///
-/// # Returns
-///
-/// Returns `Ok(())` on success, or an error if generation operations fail
-fn clean_generations(
-    runner: &dyn NixOsCommandRunner,
-    keep_last: Option<usize>,
-    dry_run: bool,
-) -> Result<()> {
-    let state = ProtectedState::load()?;
-    let current = runner.get_current_generation()?;
-    let all_generations = runner.list_generations()?;
-
-    // Collect all generation numbers
-    let mut gen_numbers: Vec<u32> = all_generations.iter().map(|g| g.number).collect();
-    gen_numbers.sort_unstable();
-
-    // Determine which generations to keep
-    let mut keep: HashSet<u32> = HashSet::new();
-
-    // Always keep current generation
-    keep.insert(current);
-
-    // Keep protected generations
-    for &protected in &state.protected_generations {
-        keep.insert(protected);
-    }
-
-    // Keep last N generations if specified
-    if let Some(n) = keep_last {
-        let start_index = gen_numbers.len().saturating_sub(n);
-        for &gen_num in &gen_numbers[start_index..] {
-            keep.insert(gen_num);
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(error::exit_code(&e))
         }
     }
-
-    // Determine which generations to delete
-    let to_delete: Vec<u32> = gen_numbers
-        .iter()
-        .filter(|&&g| !keep.contains(&g))
-        .copied()
-        .collect();
-
-    if to_delete.is_empty() {
-        println!("No generations to delete");
-        return Ok(());
-    }
-
-    if dry_run {
-        println!(
-            "[DRY RUN] Would delete {} generation(s): {:?}",
-            to_delete.len(),
-            to_delete
-        );
-        println!();
-        println!("Command that would be executed:");
-        let gen_list: Vec<String> = to_delete.iter().map(|g| g.to_string()).collect();
-        let gen_arg = gen_list.join(" ");
-        println!(
-            "  nix-env --delete-generations {} -p /nix/var/nix/profiles/system",
-            gen_arg
-        );
-    } else {
-        println!(
-            "Deleting {} generation(s): {:?}",
-            to_delete.len(),
-            to_delete
-        );
-        runner.delete_generations(&to_delete)?;
-        println!("Successfully deleted {} generation(s)", to_delete.len());
-    }
-
-    Ok(())
 }
 
-/// List all currently protected generations
-///
-/// This function loads the protection state and displays all generations that are
-/// currently marked as protected. The list is sorted in ascending order by generation
-/// number for easy reading.
-///
-/// # Returns
-///
-/// Returns `Ok(())` on success, or an error if the state cannot be loaded
-fn list_protected() -> Result<()> {
-    let state = ProtectedState::load()?;
-
-    if state.protected_generations.is_empty() {
-        println!("No protected generations");
+fn run(cli: Cli) -> Result<()> {
+    // Flags override the config files
+    let mut config = Config::load()?.merge(Config {
+        profile: cli.profile,
+        output: cli.output,
+        mode: cli.mode,
+        nix: NixConfig {
+            nix_env: cli.nix_env_bin,
+            nix: cli.nix_bin,
+            extra_args: (!cli.nix_args.is_empty()).then_some(cli.nix_args),
+            env: cli.nix_env_vars.into_iter().collect(),
+        },
+        ..Default::default()
+    });
+    let state_file = StateFile::resolve(config.mode(), cli.state_file)?
+        .with_history_size(config.state_history());
+    let parse_mode = if cli.strict {
+        ParseMode::Strict
     } else {
-        let mut protected: Vec<u32> = state.protected_generations.iter().copied().collect();
-        protected.sort_unstable();
-        println!("Protected generations:");
-        for gen_num in protected {
-            println!("  {}", gen_num);
-        }
-    }
-
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::mock_runner::MockNixOsRunner;
+        ParseMode::Lenient
+    };
+    let runner = commands::runner_for_profile(
+        config.profile(),
+        parse_mode,
+        config.nix.command(cli.verbose),
+    );
+    let runner = runner.as_ref();
+    let audit = AuditLog::open_default()?;
+    let recovery = RecoveryLog::open_default()?;
 
-    #[test]
-    fn test_clean_no_protected() {
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
-        clean_generations(&runner, None, false).unwrap();
-
-        // Should delete all except current (5)
-        assert!(runner.was_deleted(1));
-        assert!(runner.was_deleted(2));
-        assert!(runner.was_deleted(3));
-        assert!(runner.was_deleted(4));
-        assert!(!runner.was_deleted(5));
-    }
-
-    #[test]
-    fn test_clean_with_keep_last() {
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
-        clean_generations(&runner, Some(2), false).unwrap();
-
-        // Should delete 1, 2, 3 and keep 4, 5 (last 2)
-        assert!(runner.was_deleted(1));
-        assert!(runner.was_deleted(2));
-        assert!(runner.was_deleted(3));
-        assert!(!runner.was_deleted(4));
-        assert!(!runner.was_deleted(5));
-    }
-
-    #[test]
-    fn test_clean_dry_run() {
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
-        clean_generations(&runner, None, true).unwrap();
-
-        // Dry run should not delete anything
-        assert!(!runner.was_deleted(1));
-        assert!(!runner.was_deleted(2));
-        assert!(!runner.was_deleted(3));
-        assert!(!runner.was_deleted(4));
-        assert!(!runner.was_deleted(5));
-    }
-
-    #[test]
-    fn test_clean_with_protected_generations() {
-        use tempfile::TempDir;
-
-        // Create temporary config for this test
-        let tmp_dir = TempDir::new().unwrap();
-        let config_path = tmp_dir
-            .path()
-            .join("lock-generations")
-            .join("protected.json");
-
-        // Create and save protected state
-        let mut state = ProtectedState::new();
-        state.protect(2);
-        state.protect(4);
-        state.save_to(&config_path).unwrap();
-
-        // Temporarily override the config path
-        // SAFETY: This test runs in isolation and we restore the env var afterward
-        unsafe {
-            std::env::set_var("XDG_CONFIG_HOME", tmp_dir.path());
+    match cli.command {
+        Commands::Protect {
+            generation,
+            gc_root,
+            pin_profile,
+        } => commands::protect_generation(
+            runner,
+            &audit,
+            &state_file,
+            generation,
+            gc_root,
+            pin_profile,
+        ),
+        Commands::Unprotect { generation } => {
+            commands::unprotect_generation(runner, &audit, &state_file, generation)
         }
-
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
-        clean_generations(&runner, None, false).unwrap();
-
-        // Should delete 1, 3 but keep 2, 4 (protected) and 5 (current)
-        assert!(runner.was_deleted(1));
-        assert!(!runner.was_deleted(2)); // protected
-        assert!(runner.was_deleted(3));
-        assert!(!runner.was_deleted(4)); // protected
-        assert!(!runner.was_deleted(5)); // current
-
-        // SAFETY: Restoring original state
-        unsafe {
-            std::env::remove_var("XDG_CONFIG_HOME");
+        Commands::Clean {
+            retention,
+            dry_run,
+            quarantine,
+            no_quarantine,
+            grace_days,
+            wait,
+            keep_going,
+        } => {
+            config = config.merge(Config {
+                clean: CleanConfig {
+                    quarantine: (quarantine || no_quarantine).then_some(quarantine),
+                    grace_days,
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
+            let options = CleanOptions {
+                policy: retention.resolve(&config)?,
+                quarantine: config.quarantine_days(),
+                dry_run,
+                // When in doubt, root honors everybody's protections
+                all_users: paths::is_root(),
+                wait,
+                keep_going,
+                ..Default::default()
+            };
+
+            if !dry_run {
+                commands::run_hook("pre_clean", config.hooks.pre_clean.as_deref(), runner)?;
+            }
+            let report =
+                commands::clean_generations(runner, &audit, &recovery, &state_file, &options)?;
+            for (generation, error) in &report.failed {
+                eprintln!("Failed to delete generation {}: {}", generation, error);
+            }
+            if !report.deleted.is_empty() {
+                println!(
+                    "Successfully deleted {} generation(s)",
+                    report.deleted.len()
+                );
+            }
+            report.check()?;
+            if !dry_run {
+                commands::run_hook("post_clean", config.hooks.post_clean.as_deref(), runner)?;
+            }
+            Ok(())
         }
-    }
-
-    #[test]
-    fn test_clean_with_protected_and_keep_last() {
-        use tempfile::TempDir;
-
-        let tmp_dir = TempDir::new().unwrap();
-        let config_path = tmp_dir
-            .path()
-            .join("lock-generations")
-            .join("protected.json");
-
-        let mut state = ProtectedState::new();
-        state.protect(2);
-        state.save_to(&config_path).unwrap();
-
-        // SAFETY: This test runs in isolation and we restore the env var afterward
-        unsafe {
-            std::env::set_var("XDG_CONFIG_HOME", tmp_dir.path());
+        Commands::Restore { generation } => match generation {
+            Some(generation) => commands::restore_generation(runner, &audit, &recovery, generation),
+            None => commands::list_restorable(runner, &recovery),
+        },
+        Commands::List => commands::list_protected(&state_file, config.output()),
+        Commands::SyncRoots { dry_run } => {
+            commands::sync_roots(runner, &state_file, paths::is_root(), dry_run)
         }
-
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5, 6], 6);
-        clean_generations(&runner, Some(3), false).unwrap();
-
-        // Should delete 1, 3
-        // Keep: 2 (protected), 4, 5, 6 (last 3)
-        assert!(runner.was_deleted(1));
-        assert!(!runner.was_deleted(2)); // protected
-        assert!(runner.was_deleted(3));
-        assert!(!runner.was_deleted(4)); // keep_last 3
-        assert!(!runner.was_deleted(5)); // keep_last 3
-        assert!(!runner.was_deleted(6)); // keep_last 3 + current
-
-        // SAFETY: Restoring original state
-        unsafe {
-            std::env::remove_var("XDG_CONFIG_HOME");
+        Commands::PurgeQuarantine { all, dry_run } => {
+            commands::purge_quarantine(runner, &audit, &recovery, all, dry_run)
         }
-    }
-
-    #[test]
-    fn test_clean_no_generations_to_delete() {
-        let runner = MockNixOsRunner::with_current(vec![5], 5);
-        let result = clean_generations(&runner, None, false);
-
-        // Should succeed with nothing to delete
-        assert!(result.is_ok());
-        assert!(!runner.was_deleted(5));
-    }
-
-    #[test]
-    fn test_clean_all_protected() {
-        use tempfile::TempDir;
-
-        let tmp_dir = TempDir::new().unwrap();
-        let config_path = tmp_dir
-            .path()
-            .join("lock-generations")
-            .join("protected.json");
-
-        let mut state = ProtectedState::new();
-        state.protect(1);
-        state.protect(2);
-        state.protect(3);
-        state.protect(4);
-        state.save_to(&config_path).unwrap();
-
-        // SAFETY: This test runs in isolation and we restore the env var afterward
-        unsafe {
-            std::env::set_var("XDG_CONFIG_HOME", tmp_dir.path());
+        Commands::History {
+            generation,
+            action,
+            limit,
+        } => commands::show_history(&audit, generation, action, limit, config.output()),
+        Commands::Export { file } => {
+            commands::export_protections(runner, &state_file, file.as_deref())
         }
-
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 5);
-        clean_generations(&runner, None, false).unwrap();
-
-        // Nothing should be deleted (all protected or current)
-        assert!(!runner.was_deleted(1));
-        assert!(!runner.was_deleted(2));
-        assert!(!runner.was_deleted(3));
-        assert!(!runner.was_deleted(4));
-        assert!(!runner.was_deleted(5));
-
-        // SAFETY: Restoring original state
-        unsafe {
-            std::env::remove_var("XDG_CONFIG_HOME");
+        Commands::Import {
+            file,
+            replace,
+            force,
+            dry_run,
+        } => commands::import_protections(
+            runner,
+            &audit,
+            &state_file,
+            &file,
+            &ImportOptions {
+                replace,
+                force,
+                dry_run,
+            },
+        ),
+        Commands::Undo => commands::undo_change(runner, &audit, &state_file),
+        Commands::StateHistory { limit } => {
+            commands::show_state_history(&state_file, limit, config.output())?;
+            Ok(())
         }
+        Commands::Explain { retention } => {
+            let policy = retention.resolve(&config)?;
+            commands::explain_generations(
+                runner,
+                &state_file,
+                &policy,
+                paths::is_root(),
+                config.output(),
+            )?;
+            Ok(())
+        }
+        Commands::Config {
+            command: ConfigCommands::Show,
+        } => commands::show_config(&config, &state_file),
     }
+}
 
-    #[test]
-    fn test_clean_keep_last_exceeds_total() {
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 3);
-        clean_generations(&runner, Some(10), false).unwrap();
-
-        // Keep_last is larger than total, so keep everything
-        assert!(!runner.was_deleted(1));
-        assert!(!runner.was_deleted(2));
-        assert!(!runner.was_deleted(3));
-    }
-
-    #[test]
-    fn test_clean_non_sequential_generations() {
-        let runner = MockNixOsRunner::with_current(vec![1, 3, 5, 7, 10], 10);
-        clean_generations(&runner, Some(2), false).unwrap();
-
-        // Should keep last 2: 7, 10
-        assert!(runner.was_deleted(1));
-        assert!(runner.was_deleted(3));
-        assert!(runner.was_deleted(5));
-        assert!(!runner.was_deleted(7)); // keep_last 2
-        assert!(!runner.was_deleted(10)); // current
-    }
-
-    #[test]
-    fn test_clean_current_not_last_generation() {
-        // Scenario: User rolled back from generation 5 to generation 3
-        // Generations 4 and 5 exist but are newer than current
-        let runner = MockNixOsRunner::with_current(vec![1, 2, 3, 4, 5], 3);
-        clean_generations(&runner, None, false).unwrap();
-
-        // Should delete everything except current (3)
-        assert!(runner.was_deleted(1));
-        assert!(runner.was_deleted(2));
-        assert!(!runner.was_deleted(3)); // current
-        assert!(runner.was_deleted(4)); // newer than current, but not current
-        assert!(runner.was_deleted(5)); // newer than current, but not current
+/// Parse a KEY=VALUE environment variable assignment
+fn parse_env_var(assignment: &str) -> Result<(String, String)> {
+    let (key, value) = assignment
+        .split_once('=')
+        .with_context(|| format!("expected KEY=VALUE, got '{}'", assignment))?;
+    if key.is_empty() {
+        anyhow::bail!("expected KEY=VALUE, got '{}'", assignment);
     }
+    Ok((key.to_string(), value.to_string()))
 }
@@ -0,0 +1,119 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+/// Schema version written by this binary
+pub const CURRENT_VERSION: u32 = 2;
+
+/// A migration upgrading a state file from version N to N + 1
+type Migration = fn(Value) -> Result<Value>;
+
+/// Migrations indexed by the version they upgrade from
+const MIGRATIONS: &[Migration] = &[v0_to_v1, v1_to_v2];
+
+/// Read the schema version of a parsed state file
+/// Files written before versioning was introduced have no version field and count as version 0
+pub fn version_of(value: &Value) -> Result<u32> {
+    match value.get("version") {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .context("Invalid version field"),
+    }
+}
+
+/// Upgrade a parsed state file to the current schema version
+/// Files newer than this binary supports are refused rather than guessed at
+pub fn upgrade(mut value: Value) -> Result<Value> {
+    let version = version_of(&value)?;
+    if version > CURRENT_VERSION {
+        anyhow::bail!(
+            "File has schema version {}, but this version of lock-generations only supports up to version {}. \
+             Please upgrade lock-generations; the file was left untouched",
+            version,
+            CURRENT_VERSION
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        value = migration(value)
+            .with_context(|| format!("Failed to migrate from version {} to {}", from, from + 1))?;
+    }
+
+    Ok(value)
+}
+
+/// Version 0 only held `protected_generations`; version 1 adds the version field itself
+fn v0_to_v1(mut value: Value) -> Result<Value> {
+    let object = value
+        .as_object_mut()
+        .context("Expected a JSON object at the top level")?;
+    object.insert("version".to_string(), Value::from(1));
+    Ok(value)
+}
+
+/// Version 2 records which user protected each generation; older protections have no owner
+fn v1_to_v2(mut value: Value) -> Result<Value> {
+    let object = value
+        .as_object_mut()
+        .context("Expected a JSON object at the top level")?;
+    object
+        .entry("protected_by")
+        .or_insert_with(|| Value::Object(Default::default()));
+    object.insert("version".to_string(), Value::from(2));
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrations_cover_all_versions() {
+        assert_eq!(MIGRATIONS.len(), CURRENT_VERSION as usize);
+    }
+
+    #[test]
+    fn test_upgrade_v0() {
+        let value = json!({ "protected_generations": [1, 2] });
+        assert_eq!(version_of(&value).unwrap(), 0);
+
+        let upgraded = upgrade(value).unwrap();
+        assert_eq!(version_of(&upgraded).unwrap(), CURRENT_VERSION);
+        assert_eq!(upgraded["protected_generations"], json!([1, 2]));
+        assert_eq!(upgraded["protected_by"], json!({}));
+    }
+
+    #[test]
+    fn test_upgrade_v1() {
+        let value = json!({ "version": 1, "protected_generations": [5] });
+
+        let upgraded = upgrade(value).unwrap();
+        assert_eq!(version_of(&upgraded).unwrap(), 2);
+        assert_eq!(upgraded["protected_generations"], json!([5]));
+        assert_eq!(upgraded["protected_by"], json!({}));
+    }
+
+    #[test]
+    fn test_upgrade_current_is_unchanged() {
+        let value = json!({
+            "version": CURRENT_VERSION,
+            "protected_generations": [3],
+            "protected_by": { "3": "alice" },
+        });
+        assert_eq!(upgrade(value.clone()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_newer_version_is_refused() {
+        let value = json!({ "version": CURRENT_VERSION + 1, "protected_generations": [] });
+        let err = upgrade(value).unwrap_err();
+        assert!(err.to_string().contains("Please upgrade lock-generations"));
+    }
+
+    #[test]
+    fn test_invalid_version_is_refused() {
+        assert!(version_of(&json!({ "version": "one" })).is_err());
+    }
+}
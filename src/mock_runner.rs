@@ -1,15 +1,31 @@
-use crate::command_runner::{Generation, NixOsCommandRunner};
+use crate::command_runner::{GcRoot, Generation, GenerationSnapshot, NixOsCommandRunner};
 use anyhow::Result;
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
 
 /// Mock implementation of NixOsCommandRunner for testing
 /// Simulates NixOS behavior without executing real commands
 pub struct MockNixOsRunner {
+    profile_path: String,
     generations: Vec<u32>,
     current_generation: u32,
     deleted_generations: RefCell<HashSet<u32>>,
+    gc_roots: RefCell<BTreeMap<String, PathBuf>>,
+    pinned_profiles: RefCell<BTreeMap<String, PathBuf>>,
+    collected_store_paths: RefCell<HashSet<PathBuf>>,
+    created: BTreeMap<u32, u64>,
+    rebuild: Option<u32>,
+    snapshots_taken: Cell<usize>,
+    busy_checks: Cell<usize>,
     fail_on_delete: bool,
+    fail_on_generation: Option<u32>,
+    booted: Option<u32>,
+    also_delete: Option<u32>,
+    ignore_deletes: bool,
+    delete_batches: RefCell<Vec<Vec<u32>>>,
+    /// Number of add_gc_root calls that succeed before the rest fail, None for no limit
+    gc_roots_before_failure: Cell<Option<usize>>,
 }
 
 impl MockNixOsRunner {
@@ -18,51 +34,192 @@ impl MockNixOsRunner {
     pub fn new(generations: Vec<u32>) -> Self {
         let current = *generations.last().unwrap_or(&1);
         Self {
+            profile_path: "/nix/var/nix/profiles/system".to_string(),
             generations,
             current_generation: current,
             deleted_generations: RefCell::new(HashSet::new()),
+            gc_roots: RefCell::new(BTreeMap::new()),
+            pinned_profiles: RefCell::new(BTreeMap::new()),
+            collected_store_paths: RefCell::new(HashSet::new()),
+            created: BTreeMap::new(),
+            rebuild: None,
+            snapshots_taken: Cell::new(0),
+            busy_checks: Cell::new(0),
             fail_on_delete: false,
+            fail_on_generation: None,
+            booted: None,
+            also_delete: None,
+            ignore_deletes: false,
+            delete_batches: RefCell::new(Vec::new()),
+            gc_roots_before_failure: Cell::new(None),
         }
     }
 
     /// Create a new MockNixOsRunner with specified current generation
     pub fn with_current(generations: Vec<u32>, current: u32) -> Self {
         Self {
+            profile_path: "/nix/var/nix/profiles/system".to_string(),
             generations,
             current_generation: current,
             deleted_generations: RefCell::new(HashSet::new()),
+            gc_roots: RefCell::new(BTreeMap::new()),
+            pinned_profiles: RefCell::new(BTreeMap::new()),
+            collected_store_paths: RefCell::new(HashSet::new()),
+            created: BTreeMap::new(),
+            rebuild: None,
+            snapshots_taken: Cell::new(0),
+            busy_checks: Cell::new(0),
             fail_on_delete: false,
+            fail_on_generation: None,
+            booted: None,
+            also_delete: None,
+            ignore_deletes: false,
+            delete_batches: RefCell::new(Vec::new()),
+            gc_roots_before_failure: Cell::new(None),
         }
     }
 
+    /// Manage the profile at another path than the system profile
+    pub fn with_profile_path(mut self, profile_path: &str) -> Self {
+        self.profile_path = profile_path.to_string();
+        self
+    }
+
+    /// Set the creation times (seconds since the Unix epoch) of generations
+    pub fn with_created(mut self, created: &[(u32, u64)]) -> Self {
+        self.created.extend(created.iter().copied());
+        self
+    }
+
+    /// Simulate a rebuild creating `generation` right after the first snapshot
+    /// Later snapshots list it as an additional, current generation
+    pub fn rebuild_after_first_snapshot(mut self, generation: u32) -> Self {
+        self.rebuild = Some(generation);
+        self
+    }
+
+    /// Simulate a rebuild that is reported as running for the next `checks` checks
+    pub fn rebuilding_for(self, checks: usize) -> Self {
+        self.busy_checks.set(checks);
+        self
+    }
+
     /// Configure the mock to fail when delete_generations is called
     pub fn fail_on_delete(mut self) -> Self {
         self.fail_on_delete = true;
         self
     }
 
+    /// Configure delete_generations to fail when it reaches `generation`,
+    /// after deleting the generations before it, like nix-env does
+    pub fn fail_on_generation(mut self, generation: u32) -> Self {
+        self.fail_on_generation = Some(generation);
+        self
+    }
+
+    /// Configure add_gc_root to fail after `count` successful calls
+    pub fn fail_gc_root_after(self, count: usize) -> Self {
+        self.gc_roots_before_failure.set(Some(count));
+        self
+    }
+
+    /// Set the generation the simulated system was booted from
+    pub fn with_booted(mut self, generation: u32) -> Self {
+        self.booted = Some(generation);
+        self
+    }
+
+    /// Simulate a buggy runner that deletes `generation` along with whatever it is asked to
+    pub fn also_delete(mut self, generation: u32) -> Self {
+        self.also_delete = Some(generation);
+        self
+    }
+
+    /// Simulate a buggy runner that reports success without deleting anything
+    pub fn ignore_deletes(mut self) -> Self {
+        self.ignore_deletes = true;
+        self
+    }
+
+    /// The generation lists delete_generations was called with, in order
+    pub fn delete_batches(&self) -> Vec<Vec<u32>> {
+        self.delete_batches.borrow().clone()
+    }
+
     /// Check if a generation was deleted
     pub fn was_deleted(&self, generation: u32) -> bool {
         self.deleted_generations.borrow().contains(&generation)
     }
+
+    /// Fake store path used for a generation
+    pub fn store_path_for(generation: u32) -> PathBuf {
+        PathBuf::from(format!("/nix/store/{}-nixos-system", generation))
+    }
+
+    /// Check if a GC root with the given name is registered
+    pub fn has_gc_root(&self, name: &str) -> bool {
+        self.gc_roots.borrow().contains_key(name)
+    }
+
+    /// Simulate the garbage collector removing a generation's store path
+    pub fn collect_garbage(&self, generation: u32) {
+        self.collected_store_paths
+            .borrow_mut()
+            .insert(Self::store_path_for(generation));
+    }
+
+    /// Check if a dedicated profile with the given name exists
+    pub fn has_pinned_profile(&self, name: &str) -> bool {
+        self.pinned_profiles.borrow().contains_key(name)
+    }
 }
 
 impl NixOsCommandRunner for MockNixOsRunner {
-    fn list_generations(&self) -> Result<Vec<Generation>> {
+    fn snapshot(&self) -> Result<GenerationSnapshot> {
         let deleted = self.deleted_generations.borrow();
-        Ok(self
+        let mut generations: Vec<Generation> = self
             .generations
             .iter()
             .filter(|g| !deleted.contains(g))
-            .map(|&number| Generation { number })
-            .collect())
+            .map(|&number| Generation {
+                number,
+                created: self.created.get(&number).copied(),
+            })
+            .collect();
+        let mut current = self.current_generation;
+
+        if let Some(number) = self.rebuild
+            && self.snapshots_taken.get() > 0
+        {
+            generations.push(Generation {
+                number,
+                created: None,
+            });
+            current = number;
+        }
+        self.snapshots_taken.set(self.snapshots_taken.get() + 1);
+
+        let booted = self
+            .booted
+            .filter(|g| generations.iter().any(|l| l.number == *g));
+        Ok(GenerationSnapshot {
+            generations,
+            current,
+            booted,
+        })
     }
 
-    fn get_current_generation(&self) -> Result<u32> {
-        Ok(self.current_generation)
+    fn rebuild_in_progress(&self) -> Result<Option<String>> {
+        let remaining = self.busy_checks.get();
+        if remaining == 0 {
+            return Ok(None);
+        }
+        self.busy_checks.set(remaining - 1);
+        Ok(Some("switch-to-configuration is running".to_string()))
     }
 
     fn delete_generations(&self, generations: &[u32]) -> Result<()> {
+        self.delete_batches.borrow_mut().push(generations.to_vec());
         if self.fail_on_delete {
             anyhow::bail!("Simulated deletion failure");
         }
@@ -75,14 +232,88 @@ impl NixOsCommandRunner for MockNixOsRunner {
             );
         }
 
+        if self.ignore_deletes {
+            return Ok(());
+        }
+
         // Mark generations as deleted
         let mut deleted = self.deleted_generations.borrow_mut();
+        deleted.extend(self.also_delete);
         for &gen_num in generations {
+            if self.fail_on_generation == Some(gen_num) {
+                anyhow::bail!("Simulated failure deleting generation {}", gen_num);
+            }
             deleted.insert(gen_num);
         }
 
         Ok(())
     }
+
+    fn profile_path(&self) -> &str {
+        &self.profile_path
+    }
+
+    fn generation_store_path(&self, generation: u32) -> Result<PathBuf> {
+        if !self.generations.contains(&generation) || self.was_deleted(generation) {
+            anyhow::bail!("Generation {} does not exist", generation);
+        }
+        Ok(Self::store_path_for(generation))
+    }
+
+    fn add_gc_root(&self, name: &str, store_path: &Path) -> Result<()> {
+        match self.gc_roots_before_failure.get() {
+            Some(0) => anyhow::bail!("Failed to add GC root {}", name),
+            Some(count) => self.gc_roots_before_failure.set(Some(count - 1)),
+            None => {}
+        }
+        self.gc_roots
+            .borrow_mut()
+            .insert(name.to_string(), store_path.to_path_buf());
+        Ok(())
+    }
+
+    fn remove_gc_root(&self, name: &str) -> Result<bool> {
+        Ok(self.gc_roots.borrow_mut().remove(name).is_some())
+    }
+
+    fn list_gc_roots(&self) -> Result<Vec<GcRoot>> {
+        Ok(self
+            .gc_roots
+            .borrow()
+            .iter()
+            .map(|(name, store_path)| GcRoot {
+                name: name.clone(),
+                store_path: store_path.clone(),
+            })
+            .collect())
+    }
+
+    fn pin_profile(&self, name: &str, store_path: &Path) -> Result<()> {
+        self.pinned_profiles
+            .borrow_mut()
+            .insert(name.to_string(), store_path.to_path_buf());
+        Ok(())
+    }
+
+    fn unpin_profile(&self, name: &str) -> Result<bool> {
+        Ok(self.pinned_profiles.borrow_mut().remove(name).is_some())
+    }
+
+    fn store_path_exists(&self, store_path: &Path) -> Result<bool> {
+        Ok(!self.collected_store_paths.borrow().contains(store_path))
+    }
+
+    fn restore_generation(&self, generation: u32, store_path: &Path) -> Result<()> {
+        if !self.was_deleted(generation) {
+            anyhow::bail!("Generation {} already exists", generation);
+        }
+        if !self.store_path_exists(store_path)? {
+            anyhow::bail!("Store path {} does not exist", store_path.display());
+        }
+
+        self.deleted_generations.borrow_mut().remove(&generation);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -101,7 +332,7 @@ mod tests {
     #[test]
     fn test_mock_current_generation() {
         let runner = MockNixOsRunner::with_current(vec![1, 2, 3], 2);
-        assert_eq!(runner.get_current_generation().unwrap(), 2);
+        assert_eq!(runner.snapshot().unwrap().current, 2);
     }
 
     #[test]
@@ -130,6 +361,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mock_store_path() {
+        let runner = MockNixOsRunner::new(vec![1, 2, 3]);
+        assert_eq!(
+            runner.generation_store_path(2).unwrap(),
+            MockNixOsRunner::store_path_for(2)
+        );
+        assert!(runner.generation_store_path(4).is_err());
+    }
+
+    #[test]
+    fn test_mock_pin_profile() {
+        let runner = MockNixOsRunner::new(vec![1, 2, 3]);
+        runner
+            .pin_profile("keep-2", &MockNixOsRunner::store_path_for(2))
+            .unwrap();
+        assert!(runner.has_pinned_profile("keep-2"));
+
+        assert!(runner.unpin_profile("keep-2").unwrap());
+        assert!(!runner.has_pinned_profile("keep-2"));
+        assert!(!runner.unpin_profile("keep-2").unwrap());
+    }
+
     #[test]
     fn test_mock_fail_on_delete() {
         let runner = MockNixOsRunner::new(vec![1, 2, 3]).fail_on_delete();
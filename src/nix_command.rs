@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// How the runners invoke the Nix command-line tools
+///
+/// Under sudo with `secure_path`, or with several Nix installations, the binaries
+/// found in PATH may not be the right ones, so they can be given explicitly,
+/// together with extra arguments (e.g. `--option` or `--store`) and environment variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NixCommand {
+    /// nix-env binary, looked up in PATH if unset
+    pub nix_env: Option<PathBuf>,
+    /// nix binary, looked up in PATH if unset
+    pub nix: Option<PathBuf>,
+    /// Arguments passed to every invocation, right after the binary
+    pub extra_args: Vec<String>,
+    /// Environment variables set for every invocation
+    pub env: BTreeMap<String, String>,
+    /// Print each command line to stderr before running it
+    pub verbose: bool,
+}
+
+impl NixCommand {
+    /// Start a nix-env invocation
+    pub fn nix_env(&self) -> Command {
+        self.command(self.nix_env.as_deref().unwrap_or("nix-env".as_ref()))
+    }
+
+    /// Start a nix invocation
+    pub fn nix(&self) -> Command {
+        self.command(self.nix.as_deref().unwrap_or("nix".as_ref()))
+    }
+
+    fn command(&self, program: &std::path::Path) -> Command {
+        let mut command = Command::new(program);
+        command.args(&self.extra_args).envs(&self.env);
+        command
+    }
+
+    /// Run a command to completion, logging its command line in verbose mode
+    pub fn output(&self, command: &mut Command) -> io::Result<Output> {
+        if self.verbose {
+            eprintln!("+ {}", command_line(command));
+        }
+        command.output()
+    }
+}
+
+/// Format a command as a shell command line, with its environment variables in front
+pub fn command_line(command: &Command) -> String {
+    let env = command
+        .get_envs()
+        .filter_map(|(key, value)| Some(format!("{}={}", key.to_string_lossy(), quote(value?))));
+    let program = std::iter::once(quote(command.get_program()));
+    let args = command.get_args().map(quote);
+    env.chain(program).chain(args).collect::<Vec<_>>().join(" ")
+}
+
+/// Quote an argument for the shell if it contains anything but plain characters
+fn quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=+@%,".contains(c));
+    if plain {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line() {
+        let nix = NixCommand {
+            nix_env: Some(PathBuf::from("/run/current-system/sw/bin/nix-env")),
+            extra_args: vec![
+                "--option".to_string(),
+                "substitute".to_string(),
+                "false".to_string(),
+            ],
+            env: BTreeMap::from([("NIX_REMOTE".to_string(), "daemon".to_string())]),
+            ..Default::default()
+        };
+
+        let mut command = nix.nix_env();
+        command.arg("-p").arg("/nix/var/nix/profiles/my profile");
+        assert_eq!(
+            command_line(&command),
+            "NIX_REMOTE=daemon /run/current-system/sw/bin/nix-env --option substitute false \
+             -p '/nix/var/nix/profiles/my profile'"
+        );
+    }
+
+    #[test]
+    fn test_defaults_use_path() {
+        let nix = NixCommand::default();
+        assert_eq!(command_line(&nix.nix_env()), "nix-env");
+        assert_eq!(command_line(&nix.nix()), "nix");
+    }
+}
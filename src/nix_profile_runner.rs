@@ -0,0 +1,256 @@
+use crate::command_runner::{GcRoot, Generation, GenerationSnapshot, NixOsCommandRunner};
+use crate::error::{ErrorKind, ResultExt};
+use crate::generation_parser::{self, ParseError, ParseMode};
+use crate::nix_command::NixCommand;
+use crate::real_runner::RealNixOsRunner;
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Check if a profile is managed by `nix profile` rather than `nix-env`
+/// New-style profiles describe their packages in manifest.json, nix-env ones in manifest.nix
+pub fn is_nix_profile(profile_path: &Path) -> bool {
+    profile_path.join("manifest.json").exists()
+}
+
+/// Runner for profiles managed by the new `nix profile` CLI,
+/// e.g. ~/.local/state/nix/profiles/profile
+///
+/// Generations are listed with `nix profile history` and deleted by removing
+/// their profile links, since `nix profile wipe-history` can only delete by age.
+/// Everything else works on the profile links like for nix-env profiles.
+pub struct NixProfileRunner {
+    inner: RealNixOsRunner,
+    parse_mode: ParseMode,
+}
+
+impl NixProfileRunner {
+    /// Create a runner for the `nix profile` profile at the given path
+    pub fn with_profile(profile_path: String) -> Self {
+        Self {
+            inner: RealNixOsRunner::with_profile(profile_path),
+            parse_mode: ParseMode::Lenient,
+        }
+    }
+
+    /// Set how strictly the output of `nix profile history` is checked
+    pub fn with_parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.inner = self.inner.with_parse_mode(parse_mode);
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// Set how the Nix binaries are invoked
+    pub fn with_nix_command(mut self, nix: NixCommand) -> Self {
+        self.inner = self.inner.with_nix_command(nix);
+        self
+    }
+
+    /// Execute nix profile history and return the stdout
+    fn get_history_output(&self) -> Result<String> {
+        let nix = self.inner.nix_command();
+        let output = nix
+            .output(
+                nix.nix()
+                    .args(["--extra-experimental-features", "nix-command"])
+                    .args(["profile", "history", "--profile"])
+                    .arg(self.profile_path()),
+            )
+            .context("Failed to execute nix profile history")
+            .kind(ErrorKind::Runner)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("nix profile history failed: {}", stderr))
+                .kind(ErrorKind::Runner);
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// The generation the profile link points at, e.g. 7 for profile -> profile-7-link
+    fn current_generation(&self) -> Result<u32> {
+        let profile = Path::new(self.profile_path());
+        let target = fs::read_link(profile)
+            .with_context(|| format!("Failed to read profile link: {}", profile.display()))?;
+        let profile_name = profile.file_name().unwrap_or_default().to_string_lossy();
+
+        target
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(&format!("{}-", profile_name)))
+            .and_then(|name| name.strip_suffix("-link"))
+            .and_then(|number| number.parse().ok())
+            .with_context(|| {
+                format!(
+                    "{} does not point at a generation link: {}",
+                    profile.display(),
+                    target.display()
+                )
+            })
+    }
+}
+
+impl NixOsCommandRunner for NixProfileRunner {
+    fn snapshot(&self) -> Result<GenerationSnapshot> {
+        let stdout = self.get_history_output()?;
+        let versions = generation_parser::parse_profile_history(&stdout, self.parse_mode)
+            .context("Failed to parse nix profile history output")?;
+        let current = self.current_generation()?;
+        if !versions.contains(&current) {
+            return Err(ParseError::NoCurrent)
+                .context("Failed to parse nix profile history output");
+        }
+
+        Ok(GenerationSnapshot {
+            generations: versions
+                .into_iter()
+                .map(|number| Generation {
+                    number,
+                    created: self.inner.generation_created(number),
+                })
+                .collect(),
+            current,
+            booted: self.inner.booted_generation(),
+        })
+    }
+
+    fn rebuild_in_progress(&self) -> Result<Option<String>> {
+        self.inner.rebuild_in_progress()
+    }
+
+    fn delete_generations(&self, generations: &[u32]) -> Result<()> {
+        let current = self.current_generation()?;
+        if generations.contains(&current) {
+            return Err(anyhow::anyhow!(
+                "Cannot delete the current generation {}",
+                current
+            ))
+            .kind(ErrorKind::Runner);
+        }
+
+        // This is what nix profile wipe-history does for the generations it selects
+        for &generation in generations {
+            let link = self.inner.generation_link(generation);
+            match fs::remove_file(&link) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| {
+                            format!(
+                                "Failed to delete generation {}: {}",
+                                generation,
+                                link.display()
+                            )
+                        })
+                        .kind(ErrorKind::Runner);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_command(&self, generations: &[u32]) -> String {
+        let links: Vec<String> = generations
+            .iter()
+            .map(|&g| self.inner.generation_link(g).display().to_string())
+            .collect();
+        format!("rm {}", links.join(" "))
+    }
+
+    fn profile_path(&self) -> &str {
+        self.inner.profile_path()
+    }
+
+    fn generation_store_path(&self, generation: u32) -> Result<PathBuf> {
+        self.inner.generation_store_path(generation)
+    }
+
+    fn add_gc_root(&self, name: &str, store_path: &Path) -> Result<()> {
+        self.inner.add_gc_root(name, store_path)
+    }
+
+    fn remove_gc_root(&self, name: &str) -> Result<bool> {
+        self.inner.remove_gc_root(name)
+    }
+
+    fn list_gc_roots(&self) -> Result<Vec<GcRoot>> {
+        self.inner.list_gc_roots()
+    }
+
+    fn pin_profile(&self, name: &str, store_path: &Path) -> Result<()> {
+        self.inner.pin_profile(name, store_path)
+    }
+
+    fn unpin_profile(&self, name: &str) -> Result<bool> {
+        self.inner.unpin_profile(name)
+    }
+
+    fn store_path_exists(&self, store_path: &Path) -> Result<bool> {
+        self.inner.store_path_exists(store_path)
+    }
+
+    fn restore_generation(&self, generation: u32, store_path: &Path) -> Result<()> {
+        self.inner.restore_generation(generation, store_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// A profile with generation links 1 to 3, pointing at generation 3
+    fn profile_in(tmp_dir: &TempDir) -> NixProfileRunner {
+        let profile = tmp_dir.path().join("profile");
+        for generation in 1..=3 {
+            let store_path = tmp_dir.path().join(format!("store-{}", generation));
+            fs::create_dir(&store_path).unwrap();
+            fs::write(store_path.join("manifest.json"), "{}").unwrap();
+            symlink(
+                &store_path,
+                tmp_dir.path().join(format!("profile-{}-link", generation)),
+            )
+            .unwrap();
+        }
+        symlink("profile-3-link", &profile).unwrap();
+        NixProfileRunner::with_profile(profile.to_string_lossy().into_owned())
+    }
+
+    #[test]
+    fn test_is_nix_profile() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = profile_in(&tmp_dir);
+        assert!(is_nix_profile(Path::new(runner.profile_path())));
+
+        let old_style = tmp_dir.path().join("store-old");
+        fs::create_dir(&old_style).unwrap();
+        fs::write(old_style.join("manifest.nix"), "[ ]").unwrap();
+        assert!(!is_nix_profile(&old_style));
+    }
+
+    #[test]
+    fn test_current_generation() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = profile_in(&tmp_dir);
+        assert_eq!(runner.current_generation().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_delete_generations_removes_links() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = profile_in(&tmp_dir);
+
+        runner.delete_generations(&[1]).unwrap();
+        assert!(runner.generation_store_path(1).is_err());
+        assert!(runner.generation_store_path(2).is_ok());
+
+        // The current generation is never deleted, not even partially
+        let err = runner.delete_generations(&[2, 3]).unwrap_err();
+        assert_eq!(crate::error::kind_of(&err), Some(ErrorKind::Runner));
+        assert!(runner.generation_store_path(2).is_ok());
+    }
+}
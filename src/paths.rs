@@ -0,0 +1,251 @@
+use crate::fs_util::Owner;
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// Check if the process runs with root privileges
+pub fn is_root() -> bool {
+    passwd::effective_uid() == 0
+}
+
+/// Get the user that files written on behalf of the invoking user should belong to
+/// Returns None unless running as root under sudo
+pub fn sudo_owner() -> Option<Owner> {
+    if !is_root() {
+        return None;
+    }
+
+    let uid = std::env::var("SUDO_UID").ok()?.parse().ok()?;
+    let gid = std::env::var("SUDO_GID").ok()?.parse().ok()?;
+    // sudo from a root shell needs no fix-up
+    (uid != 0).then_some(Owner { uid, gid })
+}
+
+/// Get the name of the user who invoked the tool
+/// When running under sudo, this is the original user rather than root
+pub fn invoking_user_name() -> String {
+    if let Ok(sudo_user) = std::env::var("SUDO_USER") {
+        return sudo_user;
+    }
+
+    passwd::current_username().unwrap_or_else(|| passwd::current_uid().to_string())
+}
+
+/// Get the home directory of the user who invoked the tool
+/// When running under sudo, uses the original user's home directory
+pub fn invoking_user_home() -> Result<PathBuf> {
+    if let Ok(sudo_user) = std::env::var("SUDO_USER")
+        && let Some(home) = passwd::home_of_name(&sudo_user)
+    {
+        // Running under sudo - get the original user's home directory
+        return Ok(home);
+    }
+
+    // Not running under sudo (or the sudo user is unknown), use current user's home
+    current_user_home()
+}
+
+/// Get the home directories of all users on the system
+pub fn all_user_homes() -> Vec<PathBuf> {
+    let mut homes = passwd::all_homes();
+    homes.sort();
+    homes.dedup();
+    homes
+}
+
+/// Get the current user's home directory
+pub fn current_user_home() -> Result<PathBuf> {
+    // Try HOME environment variable first
+    if let Ok(home) = std::env::var("HOME") {
+        return Ok(PathBuf::from(home));
+    }
+
+    // Fall back to looking up current user
+    if let Some(home) = passwd::home_of_uid(passwd::current_uid()) {
+        return Ok(home);
+    }
+
+    anyhow::bail!("Could not determine home directory")
+}
+
+/// Get the directory for logs
+/// Uses /var/log/lock-generations when running as root, otherwise the user state directory
+pub fn log_dir() -> Result<PathBuf> {
+    if is_root() {
+        return Ok(PathBuf::from("/var/log/lock-generations"));
+    }
+    user_state_dir()
+}
+
+/// Get the directory for persistent state such as records of deleted generations
+/// Uses /var/lib/lock-generations when running as root, otherwise the user state directory
+pub fn state_dir() -> Result<PathBuf> {
+    if is_root() {
+        return Ok(PathBuf::from("/var/lib/lock-generations"));
+    }
+    user_state_dir()
+}
+
+/// Get the current user's state directory
+/// Uses XDG_STATE_HOME if set, otherwise ~/.local/state
+fn user_state_dir() -> Result<PathBuf> {
+    let state_dir = if let Ok(xdg_state) = std::env::var("XDG_STATE_HOME") {
+        PathBuf::from(xdg_state)
+    } else {
+        current_user_home()?.join(".local").join("state")
+    };
+
+    Ok(state_dir.join("lock-generations"))
+}
+
+/// User lookups through the passwd database (NSS), with the `sudo` feature
+#[cfg(feature = "sudo")]
+mod passwd {
+    use std::path::PathBuf;
+    use users::os::unix::UserExt;
+    use users::{
+        all_users, get_current_uid, get_current_username, get_effective_uid, get_user_by_name,
+        get_user_by_uid,
+    };
+
+    pub fn effective_uid() -> u32 {
+        get_effective_uid()
+    }
+
+    pub fn current_uid() -> u32 {
+        get_current_uid()
+    }
+
+    pub fn current_username() -> Option<String> {
+        get_current_username().map(|name| name.to_string_lossy().into_owned())
+    }
+
+    pub fn home_of_name(name: &str) -> Option<PathBuf> {
+        get_user_by_name(name).map(|user| user.home_dir().to_path_buf())
+    }
+
+    pub fn home_of_uid(uid: u32) -> Option<PathBuf> {
+        get_user_by_uid(uid).map(|user| user.home_dir().to_path_buf())
+    }
+
+    pub fn all_homes() -> Vec<PathBuf> {
+        // SAFETY: all_users iterates the passwd database with getpwent, which is not thread-safe.
+        // It is only called from the main thread, before any other passwd lookups are in flight.
+        let users = unsafe { all_users() };
+        users.map(|user| user.home_dir().to_path_buf()).collect()
+    }
+}
+
+/// User lookups without the `sudo` feature: ids from /proc/self/status, users from /etc/passwd
+///
+/// Users only known to NSS modules such as LDAP or systemd-homed are not found.
+#[cfg(not(feature = "sudo"))]
+mod passwd {
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A line of /etc/passwd
+    #[derive(Debug, PartialEq, Eq)]
+    struct Entry {
+        name: String,
+        uid: u32,
+        home: PathBuf,
+    }
+
+    fn parse(contents: &str) -> Vec<Entry> {
+        contents
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(':').collect();
+                let [name, _, uid, _, _, home, ..] = fields[..] else {
+                    return None;
+                };
+                Some(Entry {
+                    name: name.to_string(),
+                    uid: uid.parse().ok()?,
+                    home: PathBuf::from(home),
+                })
+            })
+            .collect()
+    }
+
+    fn entries() -> Vec<Entry> {
+        fs::read_to_string("/etc/passwd")
+            .map(|contents| parse(&contents))
+            .unwrap_or_default()
+    }
+
+    /// Real and effective uid of the process
+    fn uids() -> Option<(u32, u32)> {
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find_map(|line| line.strip_prefix("Uid:"))?;
+        let mut ids = line.split_whitespace().map(|id| id.parse().ok());
+        Some((ids.next()??, ids.next()??))
+    }
+
+    // An unknown uid is treated as an unprivileged one
+    pub fn effective_uid() -> u32 {
+        uids().map_or(u32::MAX, |(_, effective)| effective)
+    }
+
+    pub fn current_uid() -> u32 {
+        uids().map_or(u32::MAX, |(real, _)| real)
+    }
+
+    pub fn current_username() -> Option<String> {
+        let uid = current_uid();
+        entries()
+            .into_iter()
+            .find(|entry| entry.uid == uid)
+            .map(|entry| entry.name)
+    }
+
+    pub fn home_of_name(name: &str) -> Option<PathBuf> {
+        entries()
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.home)
+    }
+
+    pub fn home_of_uid(uid: u32) -> Option<PathBuf> {
+        entries()
+            .into_iter()
+            .find(|entry| entry.uid == uid)
+            .map(|entry| entry.home)
+    }
+
+    pub fn all_homes() -> Vec<PathBuf> {
+        entries().into_iter().map(|entry| entry.home).collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_parse() {
+            let passwd = "root:x:0:0:System administrator:/root:/run/current-system/sw/bin/bash\n\
+                          # comment\n\
+                          alice:x:1000:100::/home/alice:/run/current-system/sw/bin/zsh\n";
+            assert_eq!(
+                parse(passwd),
+                vec![
+                    Entry {
+                        name: "root".to_string(),
+                        uid: 0,
+                        home: PathBuf::from("/root"),
+                    },
+                    Entry {
+                        name: "alice".to_string(),
+                        uid: 1000,
+                        home: PathBuf::from("/home/alice"),
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn test_uids() {
+            assert!(uids().is_some());
+        }
+    }
+}
@@ -1,34 +1,89 @@
+use crate::error::{ErrorKind, ResultExt};
+use crate::fs_util::{self, FileAccess};
+use crate::migrations::{self, CURRENT_VERSION};
+use crate::paths;
+use crate::state_history::{DEFAULT_HISTORY_SIZE, Snapshot, StateHistory};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::fs;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use users::os::unix::UserExt;
-use users::{get_current_uid, get_user_by_name, get_user_by_uid};
+
+/// Directory of the read-only system protection layer
+/// Holds protected.json and/or drop-ins in protected.d/*.json
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/lock-generations";
+
+/// Path of the state file shared by all admins in shared mode
+pub const SHARED_STATE_PATH: &str = "/var/lib/lock-generations/state.json";
+
+/// Where the protections of the invoking user are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum StateMode {
+    /// Per-user file in ~/.config/lock-generations/protected.json
+    #[default]
+    User,
+    /// Group-writable file in /var/lib/lock-generations/state.json shared by all admins
+    Shared,
+}
+
+impl StateMode {
+    /// Ownership and permissions for files and directories written in this mode
+    pub fn file_access(self) -> FileAccess {
+        match self {
+            // Under sudo, the files stay owned by the invoking user
+            StateMode::User => FileAccess {
+                owner: paths::sudo_owner(),
+                ..Default::default()
+            },
+            // Anyone in the directory's group may update the shared state
+            StateMode::Shared => FileAccess {
+                owner: None,
+                file_mode: Some(0o664),
+                dir_mode: Some(0o2775),
+            },
+        }
+    }
+}
 
 /// Protected generations state
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProtectedState {
+    /// Schema version of the file, see the `migrations` module
+    version: u32,
+    /// Generations protected in this file; this is what gets saved
     pub protected_generations: HashSet<u32>,
+    /// Name of the user who protected each generation, if known
+    #[serde(default)]
+    protected_by: BTreeMap<u32, String>,
+    /// Generations protected by the read-only system layer, with the file declaring them
+    #[serde(skip)]
+    system_generations: BTreeMap<u32, PathBuf>,
+    /// Generations protected in other users' files or the shared state, see `merge_all_users`
+    #[serde(skip)]
+    foreign_generations: BTreeMap<u32, PathBuf>,
 }
 
 impl ProtectedState {
     /// Create a new empty ProtectedState
     pub fn new() -> Self {
         Self {
+            version: CURRENT_VERSION,
             protected_generations: HashSet::new(),
+            protected_by: BTreeMap::new(),
+            system_generations: BTreeMap::new(),
+            foreign_generations: BTreeMap::new(),
         }
     }
 
-    /// Load protected state from the default config file
-    /// Returns empty state if file doesn't exist
-    pub fn load() -> Result<Self> {
-        let path = Self::default_config_path()?;
-        Self::load_from(&path)
-    }
-
     /// Load protected state from a specific path
     /// Returns empty state if file doesn't exist
+    ///
+    /// Files written by older versions are upgraded in memory and a backup of the
+    /// original is kept next to it; the upgraded state is written on the next save.
+    /// Files written by newer versions are refused.
     pub fn load_from(path: &Path) -> Result<Self> {
         if !path.exists() {
             return Ok(Self::new());
@@ -37,38 +92,164 @@ impl ProtectedState {
         let contents = fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
 
-        let state: ProtectedState = serde_json::from_str(&contents)
-            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        let (state, version) = Self::parse(path, &contents)?;
+        if version < CURRENT_VERSION {
+            Self::backup(path, version, &contents)?;
+        }
 
         Ok(state)
     }
 
-    /// Save protected state to the default config file
-    pub fn save(&self) -> Result<()> {
-        let path = Self::default_config_path()?;
-        self.save_to(&path)
+    /// Merge in the read-only system layer from `dir`
+    /// Reads dir/protected.json and then dir/protected.d/*.json in name order; missing files are fine
+    pub fn merge_system_layer(&mut self, dir: &Path) -> Result<()> {
+        let mut files = vec![dir.join("protected.json")];
+
+        let drop_in_dir = dir.join("protected.d");
+        if drop_in_dir.is_dir() {
+            let mut drop_ins: Vec<PathBuf> = fs::read_dir(&drop_in_dir)
+                .with_context(|| format!("Failed to read directory: {}", drop_in_dir.display()))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            drop_ins.sort();
+            files.extend(drop_ins);
+        }
+
+        for file in files {
+            if !file.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read config file: {}", file.display()))?;
+            let (layer, _) = Self::parse(&file, &contents)?;
+
+            // The first file declaring a generation is reported as its source
+            for generation in layer.protected_generations {
+                self.system_generations
+                    .entry(generation)
+                    .or_insert_with(|| file.clone());
+            }
+        }
+
+        Ok(())
     }
 
-    /// Save protected state to a specific path
-    pub fn save_to(&self, path: &Path) -> Result<()> {
-        // Ensure parent directory exists
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).with_context(|| {
-                format!("Failed to create config directory: {}", parent.display())
-            })?;
+    /// Merge in the protections of the shared state at `shared_path` and of every user in `homes`
+    /// Used by clean running as root, so nobody's protections are ignored
+    pub fn merge_all_users(&mut self, shared_path: &Path, homes: &[PathBuf]) -> Result<()> {
+        let mut files = vec![shared_path.to_path_buf()];
+        files.extend(homes.iter().map(|home| {
+            home.join(".config")
+                .join("lock-generations")
+                .join("protected.json")
+        }));
+        self.merge_foreign(&files)
+    }
+
+    /// Merge in the protections of other state files; missing files are fine
+    /// Unreadable files are an error, because silently ignoring them could delete a protected generation
+    pub fn merge_foreign(&mut self, files: &[PathBuf]) -> Result<()> {
+        for file in files {
+            if !file.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(file)
+                .with_context(|| format!("Failed to read config file: {}", file.display()))?;
+            let (other, _) = Self::parse(file, &contents)?;
+
+            for generation in other.protected_generations {
+                self.foreign_generations
+                    .entry(generation)
+                    .or_insert_with(|| file.clone());
+            }
         }
 
+        Ok(())
+    }
+
+    /// Parse a state file, upgrading it to the current schema
+    /// Returns the state together with the version the file was written with
+    fn parse(path: &Path, contents: &str) -> Result<(Self, u32)> {
+        let value: serde_json::Value = serde_json::from_str(contents)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+
+        let version = migrations::version_of(&value)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        let state = Self::from_value(value)
+            .with_context(|| format!("Failed to load config file: {}", path.display()))?;
+
+        Ok((state, version))
+    }
+
+    /// Build the state from the parsed JSON of a state file, upgrading it to the current schema
+    pub fn from_value(value: serde_json::Value) -> Result<Self> {
+        let value = migrations::upgrade(value)?;
+        serde_json::from_value(value).context("Invalid protected state")
+    }
+
+    /// Keep a copy of a file written by an older version, e.g. protected.json.v0.bak
+    /// An existing backup of the same version is never overwritten
+    fn backup(path: &Path, version: u32, contents: &str) -> Result<()> {
+        let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+        backup_name.push(format!(".v{}.bak", version));
+        let backup_path = path.with_file_name(backup_name);
+
+        if !backup_path.exists() {
+            let access = fs_util::FileAccess {
+                owner: paths::sudo_owner(),
+                ..Default::default()
+            };
+            fs_util::write_atomic_as(&backup_path, contents.as_bytes(), access).with_context(
+                || format!("Failed to back up config file: {}", backup_path.display()),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Save protected state to a specific path, the way a per-user state file is saved
+    /// Callers doing load-modify-save should hold an exclusive `StateLock` throughout.
+    /// Unlike `StateFile::save`, no snapshot is kept for `undo`.
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        self.save_to_as(path, StateMode::User.file_access())
+    }
+
+    /// Save protected state to a specific path, applying `access` to what gets created
+    fn save_to_as(&self, path: &Path, access: FileAccess) -> Result<()> {
         // Serialize to JSON with pretty printing
         let contents =
             serde_json::to_string_pretty(self).context("Failed to serialize protected state")?;
 
-        // Write atomically by writing to a temp file and renaming
-        let tmp_path = path.with_extension("tmp");
-        fs::write(&tmp_path, contents)
-            .with_context(|| format!("Failed to write temp file: {}", tmp_path.display()))?;
+        // Write atomically by writing to a unique temp file and renaming
+        fs_util::write_atomic_as(path, contents.as_bytes(), access)
+            .with_context(|| format!("Failed to save config file: {}", path.display()))
+    }
 
-        fs::rename(&tmp_path, path)
-            .with_context(|| format!("Failed to save config file: {}", path.display()))?;
+    /// Refuse to use the default state file or its directory when owned by root and not
+    /// running as root
+    /// Older versions left them root-owned after saving under sudo, which makes every later
+    /// save fail; report it with the command that fixes it instead. Only call this for the
+    /// default per-user file, whose directory belongs to lock-generations alone.
+    fn check_ownership(path: &Path, running_as_root: bool) -> Result<()> {
+        if running_as_root {
+            return Ok(());
+        }
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for candidate in [dir, path] {
+            if let Ok(metadata) = fs::metadata(candidate)
+                && metadata.uid() == 0
+            {
+                anyhow::bail!(
+                    "{} is owned by root, probably because it was saved under sudo by an older \
+                     version of lock-generations. Fix it with:\n  sudo chown -R {}: {}",
+                    candidate.display(),
+                    paths::invoking_user_name(),
+                    dir.display()
+                );
+            }
+        }
 
         Ok(())
     }
@@ -78,15 +259,56 @@ impl ProtectedState {
         self.protected_generations.insert(generation)
     }
 
+    /// Add a generation to the protected list, recording who protected it
+    pub fn protect_as(&mut self, generation: u32, user: &str) -> bool {
+        let added = self.protect(generation);
+        if added {
+            self.protected_by.insert(generation, user.to_string());
+        }
+        added
+    }
+
     /// Remove a generation from the protected list
     pub fn unprotect(&mut self, generation: u32) -> bool {
+        self.protected_by.remove(&generation);
         self.protected_generations.remove(&generation)
     }
 
-    /// Check if a generation is protected
-    #[allow(dead_code)]
+    /// Get the name of the user who protected a generation, if known
+    pub fn protected_by(&self, generation: u32) -> Option<&str> {
+        self.protected_by.get(&generation).map(String::as_str)
+    }
+
+    /// Check if a generation is protected, in this file, the system layer or a merged file
     pub fn is_protected(&self, generation: u32) -> bool {
         self.protected_generations.contains(&generation)
+            || self.system_generations.contains_key(&generation)
+            || self.foreign_generations.contains_key(&generation)
+    }
+
+    /// Get the file of the system layer protecting a generation, if any
+    pub fn system_source(&self, generation: u32) -> Option<&Path> {
+        self.system_generations
+            .get(&generation)
+            .map(PathBuf::as_path)
+    }
+
+    /// Get all protected generations of all layers, in ascending order
+    pub fn all_protected(&self) -> BTreeSet<u32> {
+        self.protected_generations
+            .iter()
+            .chain(self.system_generations.keys())
+            .chain(self.foreign_generations.keys())
+            .copied()
+            .collect()
+    }
+
+    /// Get the default config file path for `mode`
+    pub fn config_path(mode: StateMode) -> Result<PathBuf> {
+        match mode {
+            StateMode::User => Self::default_config_path(),
+            StateMode::Shared => Ok(PathBuf::from(SHARED_STATE_PATH)),
+        }
     }
 
     /// Get the default config file path
@@ -97,40 +319,231 @@ impl ProtectedState {
             // XDG_CONFIG_HOME is set, use it directly
             PathBuf::from(xdg_config)
         } else {
-            // Check if we're running under sudo
-            let home = if let Ok(sudo_user) = std::env::var("SUDO_USER") {
-                // Running under sudo - get the original user's home directory
-                if let Some(user) = get_user_by_name(&sudo_user) {
-                    user.home_dir().to_path_buf()
-                } else {
-                    // Fall back to current user if we can't find sudo user
-                    Self::get_current_user_home()?
-                }
-            } else {
-                // Not running under sudo, use current user's home
-                Self::get_current_user_home()?
-            };
-
-            home.join(".config")
+            paths::invoking_user_home()?.join(".config")
         };
 
         Ok(config_dir.join("lock-generations").join("protected.json"))
     }
+}
+
+/// The state file the commands work on, resolved once at startup
+///
+/// Carries the path together with how it is accessed, so the command functions get
+/// it passed in instead of looking it up in the environment.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    mode: StateMode,
+    /// Directory of the read-only system layer merged in on load, if any
+    system_dir: Option<PathBuf>,
+    /// Snapshots of the state taken before each save, used by `undo`
+    history: StateHistory,
+    /// Shared state merged in by `load_all_users`
+    shared_path: PathBuf,
+    /// Home directories whose protections `load_all_users` merges in; None for all users in passwd
+    user_homes: Option<Vec<PathBuf>>,
+    /// Whether this is the default per-user file, whose ownership `load` checks
+    default_user_path: bool,
+}
+
+impl StateFile {
+    /// A state file at `path`, accessed the way `mode` prescribes
+    pub fn new(path: PathBuf, mode: StateMode) -> Self {
+        Self {
+            history: StateHistory::for_state_file(&path, DEFAULT_HISTORY_SIZE),
+            path,
+            mode,
+            system_dir: Some(PathBuf::from(SYSTEM_CONFIG_DIR)),
+            shared_path: PathBuf::from(SHARED_STATE_PATH),
+            user_homes: None,
+            default_user_path: false,
+        }
+    }
+
+    /// Keep up to `size` snapshots for `undo`; 0 disables the history
+    pub fn with_history_size(mut self, size: usize) -> Self {
+        self.history = StateHistory::for_state_file(&self.path, size);
+        self
+    }
+
+    /// Resolve the state file: an explicit `path` wins over the default file of `mode`
+    /// Only the default per-user file gets its ownership checked on load; an explicit path
+    /// may well be owned by someone else on purpose
+    pub fn resolve(mode: StateMode, path: Option<PathBuf>) -> Result<Self> {
+        let default_user_path = path.is_none() && mode == StateMode::User;
+        let path = match path {
+            Some(path) => path,
+            None => ProtectedState::config_path(mode)?,
+        };
+        Ok(Self {
+            default_user_path,
+            ..Self::new(path, mode)
+        })
+    }
+
+    /// Merge the system layer from another directory instead of /etc/lock-generations,
+    /// or none at all
+    pub fn with_system_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.system_dir = dir;
+        self
+    }
+
+    /// Take the protections of other users from these places instead of the shared
+    /// state file and the home directories of all users in passwd, e.g. in a container
+    pub fn with_all_users(mut self, shared_path: PathBuf, user_homes: Vec<PathBuf>) -> Self {
+        self.shared_path = shared_path;
+        self.user_homes = Some(user_homes);
+        self
+    }
+
+    /// Get the path of the state file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-    /// Get the current user's home directory
-    fn get_current_user_home() -> Result<PathBuf> {
-        // Try HOME environment variable first
-        if let Ok(home) = std::env::var("HOME") {
-            return Ok(PathBuf::from(home));
+    /// Get the mode the state file is accessed in
+    pub fn mode(&self) -> StateMode {
+        self.mode
+    }
+
+    /// Load the protected state, merged with the system layer
+    /// Returns empty state if neither exists
+    pub fn load(&self) -> Result<ProtectedState> {
+        if self.default_user_path {
+            ProtectedState::check_ownership(&self.path, paths::is_root())
+                .kind(ErrorKind::PermissionDenied)?;
+        }
+
+        let mut state = ProtectedState::load_from(&self.path).kind(ErrorKind::State)?;
+        if let Some(dir) = &self.system_dir {
+            state.merge_system_layer(dir).kind(ErrorKind::State)?;
         }
+        Ok(state)
+    }
+
+    /// Load the protected state like `load`, merged with the protections of every
+    /// user and of the shared state
+    pub fn load_all_users(&self) -> Result<ProtectedState> {
+        let mut state = self.load()?;
+        let homes = match &self.user_homes {
+            Some(homes) => homes.clone(),
+            None => paths::all_user_homes(),
+        };
+        state
+            .merge_all_users(&self.shared_path, &homes)
+            .kind(ErrorKind::State)?;
+        Ok(state)
+    }
+
+    /// Save the protected state, keeping a snapshot of the previous one
+    /// Callers doing load-modify-save should hold the exclusive `lock` throughout
+    /// The snapshot is only recorded once the save went through, so a failed save leaves
+    /// nothing behind for `undo` to revert
+    pub fn save(&self, state: &ProtectedState) -> Result<()> {
+        let access = self.mode.file_access();
+        let snapshot = self.history.snapshot(&self.path).kind(ErrorKind::State)?;
+        state
+            .save_to_as(&self.path, access)
+            .kind(ErrorKind::State)?;
+        if let Some(snapshot) = snapshot {
+            self.history.push(snapshot, access).kind(ErrorKind::State)?;
+        }
+        Ok(())
+    }
+
+    /// Get the snapshots taken before each save, oldest first
+    pub fn history(&self) -> Result<Vec<Snapshot>> {
+        self.history.entries().kind(ErrorKind::State)
+    }
 
-        // Fall back to looking up current user
-        let uid = get_current_uid();
-        if let Some(user) = get_user_by_uid(uid) {
-            return Ok(user.home_dir().to_path_buf());
+    /// Put back the state from before the most recent save
+    /// Returns the snapshot restored, or None if there is nothing to undo.
+    /// Callers should hold the exclusive `lock`.
+    pub fn undo(&self) -> Result<Option<Snapshot>> {
+        let access = self.mode.file_access();
+        let Some(snapshot) = self.history.pop(access).kind(ErrorKind::State)? else {
+            return Ok(None);
+        };
+
+        match &snapshot.state {
+            Some(value) => {
+                let contents = serde_json::to_string_pretty(value)
+                    .context("Failed to serialize protected state")?;
+                fs_util::write_atomic_as(&self.path, contents.as_bytes(), access)
+                    .with_context(|| format!("Failed to save config file: {}", self.path.display()))
+                    .kind(ErrorKind::State)?;
+            }
+            // The state file did not exist before; an empty state is equivalent
+            None => ProtectedState::new()
+                .save_to_as(&self.path, access)
+                .kind(ErrorKind::State)?,
         }
+        Ok(Some(snapshot))
+    }
 
-        anyhow::bail!("Could not determine home directory")
+    /// Take an exclusive lock on the state file, blocking until it is available
+    pub fn lock(&self) -> Result<StateLock> {
+        StateLock::exclusive(&self.path, self.mode.file_access()).kind(ErrorKind::State)
+    }
+
+    /// Take a shared lock on the state file, blocking until it is available
+    /// Shared locks keep protections from changing while they are held
+    pub fn lock_shared(&self) -> Result<StateLock> {
+        StateLock::shared(&self.path, self.mode.file_access()).kind(ErrorKind::State)
+    }
+}
+
+/// Advisory lock on a state file, released when dropped
+///
+/// The lock is taken on a separate `<file>.lock` file, because the state file itself
+/// is replaced on every save.
+pub struct StateLock {
+    _file: File,
+}
+
+impl StateLock {
+    /// Take an exclusive lock for a load-modify-save cycle of the state file at `path`
+    /// `access` applies to the lock file and directories if they need to be created
+    pub fn exclusive(path: &Path, access: FileAccess) -> Result<Self> {
+        let file = Self::open_lock_file(path, access)?;
+        file.lock()
+            .with_context(|| format!("Failed to lock config file: {}", path.display()))?;
+        Ok(Self { _file: file })
+    }
+
+    /// Take a shared lock, keeping the state file at `path` from changing while held
+    pub fn shared(path: &Path, access: FileAccess) -> Result<Self> {
+        let file = Self::open_lock_file(path, access)?;
+        file.lock_shared()
+            .with_context(|| format!("Failed to lock config file: {}", path.display()))?;
+        Ok(Self { _file: file })
+    }
+
+    fn open_lock_file(path: &Path, access: FileAccess) -> Result<File> {
+        let mut lock_name = path.file_name().unwrap_or_default().to_os_string();
+        lock_name.push(".lock");
+        let lock_path = path.with_file_name(lock_name);
+
+        if let Some(parent) = lock_path.parent() {
+            fs_util::create_dir_all_as(parent, access).with_context(|| {
+                format!("Failed to create config directory: {}", parent.display())
+            })?;
+        }
+
+        let existed = lock_path.exists();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file: {}", lock_path.display()))?;
+
+        if !existed {
+            fs_util::apply_file_access(&lock_path, access)?;
+        }
+
+        Ok(file)
     }
 }
 
@@ -192,4 +605,255 @@ mod tests {
         let state = ProtectedState::load_from(&config_path).unwrap();
         assert!(state.protected_generations.is_empty());
     }
+
+    #[test]
+    fn test_save_writes_current_version() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("protected.json");
+
+        ProtectedState::new().save_to(&config_path).unwrap();
+
+        let value: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(value["version"], CURRENT_VERSION);
+    }
+
+    #[test]
+    fn test_load_unversioned_file() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("protected.json");
+        let original = r#"{"protected_generations": [4, 8]}"#;
+        fs::write(&config_path, original).unwrap();
+
+        let state = ProtectedState::load_from(&config_path).unwrap();
+        assert!(state.is_protected(4));
+        assert!(state.is_protected(8));
+        assert_eq!(state.version, CURRENT_VERSION);
+
+        // The original is kept as a backup
+        let backup = tmp_dir.path().join("protected.json.v0.bak");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), original);
+    }
+
+    #[test]
+    fn test_load_newer_version_is_refused() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("protected.json");
+        let newer = format!(
+            r#"{{"version": {}, "protected_generations": [1]}}"#,
+            CURRENT_VERSION + 1
+        );
+        fs::write(&config_path, &newer).unwrap();
+
+        assert!(ProtectedState::load_from(&config_path).is_err());
+        // The file is left untouched
+        assert_eq!(fs::read_to_string(&config_path).unwrap(), newer);
+    }
+
+    #[test]
+    fn test_protected_by() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("state.json");
+
+        let mut state = ProtectedState::new();
+        assert!(state.protect_as(1, "alice"));
+        assert!(!state.protect_as(1, "bob")); // The first protector is kept
+        assert!(state.protect_as(2, "bob"));
+        state.save_to(&config_path).unwrap();
+
+        let mut loaded = ProtectedState::load_from(&config_path).unwrap();
+        assert_eq!(loaded.protected_by(1), Some("alice"));
+        assert_eq!(loaded.protected_by(2), Some("bob"));
+
+        loaded.unprotect(1);
+        assert_eq!(loaded.protected_by(1), None);
+    }
+
+    #[test]
+    fn test_merge_foreign() {
+        let tmp_dir = TempDir::new().unwrap();
+        let alice = tmp_dir.path().join("alice.json");
+        let bob = tmp_dir.path().join("bob.json");
+
+        let mut state = ProtectedState::new();
+        state.protect(1);
+        state.save_to(&alice).unwrap();
+        let mut state = ProtectedState::new();
+        state.protect(2);
+        state.save_to(&bob).unwrap();
+
+        let mut merged = ProtectedState::new();
+        merged.protect(3);
+        merged
+            .merge_foreign(&[alice, bob, tmp_dir.path().join("missing.json")])
+            .unwrap();
+
+        assert_eq!(
+            merged.all_protected().into_iter().collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        // Merged protections are never written back
+        assert_eq!(merged.protected_generations.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_foreign_corrupt_file_is_an_error() {
+        let tmp_dir = TempDir::new().unwrap();
+        let corrupt = tmp_dir.path().join("corrupt.json");
+        fs::write(&corrupt, "{").unwrap();
+
+        let mut state = ProtectedState::new();
+        assert!(state.merge_foreign(&[corrupt]).is_err());
+    }
+
+    #[test]
+    fn test_resolve_state_file() {
+        // --state-file and LOCK_GENERATIONS_STATE both arrive as an explicit path
+        let explicit = PathBuf::from("/srv/lock-generations/protected.json");
+        let state_file = StateFile::resolve(StateMode::User, Some(explicit.clone())).unwrap();
+        assert_eq!(state_file.path(), explicit);
+        assert!(!state_file.default_user_path);
+
+        let state_file = StateFile::resolve(StateMode::Shared, None).unwrap();
+        assert_eq!(state_file.path(), Path::new(SHARED_STATE_PATH));
+        assert!(!state_file.default_user_path);
+
+        // Only the default per-user file has its ownership checked
+        if let Ok(default) = ProtectedState::config_path(StateMode::User) {
+            let state_file = StateFile::resolve(StateMode::User, None).unwrap();
+            assert_eq!(state_file.path(), default);
+            assert!(state_file.default_user_path);
+        }
+    }
+
+    #[test]
+    fn test_root_owned_file_is_reported() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("protected.json");
+        ProtectedState::new().save_to(&config_path).unwrap();
+
+        // Nothing to report when running as root
+        assert!(ProtectedState::check_ownership(&config_path, true).is_ok());
+
+        // Files created by the test are only root-owned when the tests run as root
+        if paths::is_root() {
+            let err = ProtectedState::check_ownership(&config_path, false).unwrap_err();
+            assert!(err.to_string().contains("sudo chown -R"));
+        }
+    }
+
+    #[test]
+    fn test_merge_system_layer() {
+        let tmp_dir = TempDir::new().unwrap();
+        let system_dir = tmp_dir.path().join("etc");
+        let drop_in_dir = system_dir.join("protected.d");
+        fs::create_dir_all(&drop_in_dir).unwrap();
+        fs::write(
+            system_dir.join("protected.json"),
+            r#"{"protected_generations": [1]}"#,
+        )
+        .unwrap();
+        fs::write(
+            drop_in_dir.join("10-base.json"),
+            r#"{"version": 1, "protected_generations": [1, 2]}"#,
+        )
+        .unwrap();
+        // Only .json files are drop-ins
+        fs::write(drop_in_dir.join("README"), "not json").unwrap();
+
+        let mut state = ProtectedState::new();
+        state.protect(3);
+        state.merge_system_layer(&system_dir).unwrap();
+
+        assert!(state.is_protected(1));
+        assert!(state.is_protected(2));
+        assert!(state.is_protected(3));
+        assert_eq!(
+            state.all_protected().into_iter().collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            state.system_source(1),
+            Some(system_dir.join("protected.json").as_path())
+        );
+        assert_eq!(
+            state.system_source(2),
+            Some(drop_in_dir.join("10-base.json").as_path())
+        );
+        assert_eq!(state.system_source(3), None);
+    }
+
+    #[test]
+    fn test_system_layer_is_not_saved() {
+        let tmp_dir = TempDir::new().unwrap();
+        let system_dir = tmp_dir.path().join("etc");
+        fs::create_dir_all(&system_dir).unwrap();
+        fs::write(
+            system_dir.join("protected.json"),
+            r#"{"protected_generations": [7]}"#,
+        )
+        .unwrap();
+
+        let config_path = tmp_dir.path().join("protected.json");
+        let mut state = ProtectedState::new();
+        state.merge_system_layer(&system_dir).unwrap();
+        state.protect(8);
+        state.save_to(&config_path).unwrap();
+
+        let loaded = ProtectedState::load_from(&config_path).unwrap();
+        assert!(!loaded.is_protected(7));
+        assert!(loaded.is_protected(8));
+    }
+
+    #[test]
+    fn test_missing_system_layer() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut state = ProtectedState::new();
+        state
+            .merge_system_layer(&tmp_dir.path().join("missing"))
+            .unwrap();
+        assert!(state.all_protected().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_not_lost() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("protected.json");
+
+        let handles: Vec<_> = (0..8)
+            .map(|generation| {
+                let config_path = config_path.clone();
+                std::thread::spawn(move || {
+                    let _lock = StateLock::exclusive(&config_path, FileAccess::default()).unwrap();
+                    let mut state = ProtectedState::load_from(&config_path).unwrap();
+                    state.protect(generation);
+                    state.save_to(&config_path).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let loaded = ProtectedState::load_from(&config_path).unwrap();
+        assert_eq!(loaded.protected_generations.len(), 8);
+    }
+
+    #[test]
+    fn test_shared_lock_blocks_writers() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("protected.json");
+
+        let shared = StateLock::shared(&config_path, FileAccess::default()).unwrap();
+        // Other readers are fine
+        let other_reader = StateLock::shared(&config_path, FileAccess::default()).unwrap();
+
+        let lock_file = tmp_dir.path().join("protected.json.lock");
+        let writer = File::open(&lock_file).unwrap();
+        assert!(writer.try_lock().is_err());
+
+        drop(shared);
+        drop(other_reader);
+        assert!(writer.try_lock().is_ok());
+    }
 }
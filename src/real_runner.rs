@@ -1,25 +1,190 @@
-use crate::command_runner::{Generation, NixOsCommandRunner};
+use crate::command_runner::{
+    DEFAULT_PROFILE_PATH, GcRoot, Generation, GenerationSnapshot, NixOsCommandRunner,
+};
+use crate::error::{ErrorKind, ResultExt};
+use crate::generation_parser::{self, ParseMode};
+use crate::nix_command::{self, NixCommand};
 use anyhow::{Context, Result};
-use std::process::Command;
+use std::ffi::OsStr;
+use std::fs::{self, File, TryLockError};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+/// Directory holding the GC roots registered by this tool
+const DEFAULT_GC_ROOT_DIR: &str = "/nix/var/nix/gcroots/lock-generations";
+
+/// Lock held by the garbage collector while it runs
+const GC_LOCK_PATH: &str = "/nix/var/nix/gc.lock";
+
+/// Directory of the extra system profiles created by `nixos-rebuild --profile-name`
+const SYSTEM_PROFILES_DIR: &str = "/nix/var/nix/profiles/system-profiles";
+
+/// Link to the system the machine was booted from
+const BOOTED_SYSTEM_PATH: &str = "/run/booted-system";
 
 /// Real implementation of NixOsCommandRunner that executes actual nix-env commands
 pub struct RealNixOsRunner {
     profile_path: String,
+    gc_root_dir: PathBuf,
+    gc_lock: PathBuf,
+    parse_mode: ParseMode,
+    nix: NixCommand,
+    booted_system: PathBuf,
 }
 
 impl RealNixOsRunner {
     /// Create a new RealNixOsRunner with the default system profile path
     pub fn new() -> Self {
-        Self {
-            profile_path: "/nix/var/nix/profiles/system".to_string(),
-        }
+        Self::with_profile(DEFAULT_PROFILE_PATH.to_string())
     }
 
     /// Create a new RealNixOsRunner with a custom profile path (useful for testing)
-    #[allow(dead_code)]
     pub fn with_profile(profile_path: String) -> Self {
-        Self { profile_path }
+        Self {
+            profile_path,
+            gc_root_dir: PathBuf::from(DEFAULT_GC_ROOT_DIR),
+            gc_lock: PathBuf::from(GC_LOCK_PATH),
+            parse_mode: ParseMode::Lenient,
+            nix: NixCommand::default(),
+            booted_system: PathBuf::from(BOOTED_SYSTEM_PATH),
+        }
+    }
+
+    /// Set how strictly the output of nix-env is checked
+    pub fn with_parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// Set how the Nix binaries are invoked
+    pub fn with_nix_command(mut self, nix: NixCommand) -> Self {
+        self.nix = nix;
+        self
+    }
+
+    /// How the Nix binaries are invoked
+    #[cfg(feature = "nix-profile")]
+    pub(crate) fn nix_command(&self) -> &NixCommand {
+        &self.nix
+    }
+
+    /// Use a custom link to the booted system (useful for testing)
+    pub fn with_booted_system(mut self, booted_system: PathBuf) -> Self {
+        self.booted_system = booted_system;
+        self
+    }
+
+    /// Use a custom lock file for detecting a running garbage collection (useful for testing)
+    pub fn with_gc_lock(mut self, gc_lock: PathBuf) -> Self {
+        self.gc_lock = gc_lock;
+        self
+    }
+
+    /// Use a custom directory for GC roots (useful for testing)
+    pub fn with_gc_root_dir(mut self, gc_root_dir: PathBuf) -> Self {
+        self.gc_root_dir = gc_root_dir;
+        self
+    }
+
+    /// Path of the profile link for a generation, e.g. /nix/var/nix/profiles/system-42-link
+    pub(crate) fn generation_link(&self, generation: u32) -> PathBuf {
+        PathBuf::from(format!("{}-{}-link", self.profile_path, generation))
+    }
+
+    /// Creation time of a generation, taken from the modification time of its profile link
+    /// nix-env prints the same time, but in the local time zone
+    pub(crate) fn generation_created(&self, generation: u32) -> Option<u64> {
+        let modified = fs::symlink_metadata(self.generation_link(generation))
+            .ok()?
+            .modified()
+            .ok()?;
+        modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    }
+
+    /// The generation whose store path the system was booted from, if any
+    /// Profiles other than the system profile never have a booted generation
+    pub(crate) fn booted_generation(&self) -> Option<u32> {
+        let booted = fs::read_link(&self.booted_system).ok()?;
+        let profile = Path::new(&self.profile_path);
+        let prefix = format!("{}-", profile.file_name()?.to_str()?);
+        fs::read_dir(profile.parent()?)
+            .ok()?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name();
+                let number = name
+                    .to_str()?
+                    .strip_prefix(&prefix)?
+                    .strip_suffix("-link")?;
+                number.parse::<u32>().ok()
+            })
+            .filter(|&generation| {
+                self.generation_store_path(generation)
+                    .is_ok_and(|store_path| store_path == booted)
+            })
+            .max()
+    }
+
+    /// Check if the profile is the system profile or one of the extra system profiles
+    fn is_system_profile(&self) -> bool {
+        self.profile_path == DEFAULT_PROFILE_PATH
+            || Path::new(&self.profile_path).parent() == Some(Path::new(SYSTEM_PROFILES_DIR))
+    }
+
+    /// Directory holding extra system profiles, e.g. /nix/var/nix/profiles/system-profiles
+    fn system_profiles_dir(&self) -> PathBuf {
+        let profile = Path::new(&self.profile_path);
+        profile
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .join("system-profiles")
+    }
+}
+
+/// Check if another process holds an exclusive lock on a file
+/// Nix takes its profile and GC locks with flock, so a shared try-lock fails while they are held
+fn is_locked(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    matches!(file.try_lock_shared(), Err(TryLockError::WouldBlock))
+}
+
+/// Find a running process running the program `name`, returning its pid
+fn find_process(name: &str) -> Option<u32> {
+    let own_pid = std::process::id();
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+        if pid == own_pid {
+            return None;
+        }
+        let cmdline = fs::read(entry.path().join("cmdline")).ok()?;
+        runs_program(&cmdline, name).then_some(pid)
+    })
+}
+
+/// Check if a NUL-separated command line runs the program `name`
+/// The base name of argv[0] must match exactly; for a script run through perl, as older
+/// NixOS versions run switch-to-configuration, the script in argv[1] counts instead
+fn runs_program(cmdline: &[u8], name: &str) -> bool {
+    let mut args = cmdline
+        .split(|&b| b == 0)
+        .map(|arg| Path::new(OsStr::from_bytes(arg)));
+    let Some(program) = args.next().and_then(Path::file_name) else {
+        return false;
+    };
+    if program == "perl" {
+        return args
+            .next()
+            .and_then(Path::file_name)
+            .is_some_and(|script| script == name);
     }
+    program == name
 }
 
 impl Default for RealNixOsRunner {
@@ -29,18 +194,38 @@ impl Default for RealNixOsRunner {
 }
 
 impl RealNixOsRunner {
+    /// Build nix-env --delete-generations for the given generations
+    fn delete_generations_command(&self, generations: &[u32]) -> std::process::Command {
+        let mut command = self.nix.nix_env();
+        command
+            .arg("--delete-generations")
+            .args(generations.iter().map(|g| g.to_string()))
+            .arg("-p")
+            .arg(&self.profile_path);
+        command
+    }
+
     /// Execute nix-env --list-generations and return the stdout
     fn get_generations_output(&self) -> Result<String> {
-        let output = Command::new("nix-env")
-            .arg("--list-generations")
-            .arg("-p")
-            .arg(&self.profile_path)
-            .output()
-            .context("Failed to execute nix-env --list-generations")?;
+        let output = self
+            .nix
+            .output(
+                self.nix
+                    .nix_env()
+                    .arg("--list-generations")
+                    .arg("-p")
+                    .arg(&self.profile_path),
+            )
+            .context("Failed to execute nix-env --list-generations")
+            .kind(ErrorKind::Runner)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("nix-env --list-generations failed: {}", stderr);
+            return Err(anyhow::anyhow!(
+                "nix-env --list-generations failed: {}",
+                stderr
+            ))
+            .kind(ErrorKind::Runner);
         }
 
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
@@ -48,69 +233,427 @@ impl RealNixOsRunner {
 }
 
 impl NixOsCommandRunner for RealNixOsRunner {
-    fn list_generations(&self) -> Result<Vec<Generation>> {
+    fn snapshot(&self) -> Result<GenerationSnapshot> {
         let stdout = self.get_generations_output()?;
-        let mut generations = Vec::new();
-
-        // Parse output format:
-        //   1   2024-01-15 10:30:45
-        //   2   2024-01-16 14:20:10
-        //   3   2024-01-17 09:15:30   (current)
-        for line in stdout.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
+        let listing = generation_parser::parse_generations(&stdout, self.parse_mode)
+            .context("Failed to parse nix-env --list-generations output")?;
+        for skipped in &listing.skipped {
+            eprintln!(
+                "Warning: ignoring line {} of nix-env --list-generations output: {:?}",
+                skipped.line_number, skipped.line
+            );
+        }
 
-            // Extract the generation number (first token)
-            if let Some(number_str) = line.split_whitespace().next()
-                && let Ok(number) = number_str.parse::<u32>()
-            {
-                generations.push(Generation { number });
-            }
+        Ok(GenerationSnapshot {
+            generations: listing
+                .generations
+                .into_iter()
+                .map(|number| Generation {
+                    number,
+                    created: self.generation_created(number),
+                })
+                .collect(),
+            current: listing.current,
+            booted: self.booted_generation(),
+        })
+    }
+
+    fn rebuild_in_progress(&self) -> Result<Option<String>> {
+        let profile_lock = PathBuf::from(format!("{}.lock", self.profile_path));
+        if is_locked(&profile_lock) {
+            return Ok(Some(format!(
+                "{} is held by another Nix process",
+                profile_lock.display()
+            )));
+        }
+        // Only a NixOS switch runs switch-to-configuration, and it never touches other profiles
+        if self.is_system_profile()
+            && let Some(pid) = find_process("switch-to-configuration")
+        {
+            return Ok(Some(format!(
+                "switch-to-configuration is running (pid {})",
+                pid
+            )));
+        }
+        if is_locked(&self.gc_lock) {
+            return Ok(Some("the Nix garbage collector is running".to_string()));
+        }
+        Ok(None)
+    }
+
+    fn delete_generations(&self, generations: &[u32]) -> Result<()> {
+        if generations.is_empty() {
+            return Ok(());
         }
 
-        Ok(generations)
+        // Execute: nix-env --delete-generations 1 2 3 -p /nix/var/nix/profiles/system
+        let output = self
+            .nix
+            .output(&mut self.delete_generations_command(generations))
+            .context("Failed to execute nix-env --delete-generations")
+            .kind(ErrorKind::Runner)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!(
+                "nix-env --delete-generations failed: {}",
+                stderr
+            ))
+            .kind(ErrorKind::Runner);
+        }
+
+        Ok(())
     }
 
-    fn get_current_generation(&self) -> Result<u32> {
-        let stdout = self.get_generations_output()?;
+    fn delete_command(&self, generations: &[u32]) -> String {
+        nix_command::command_line(&self.delete_generations_command(generations))
+    }
+
+    fn profile_path(&self) -> &str {
+        &self.profile_path
+    }
+
+    fn generation_store_path(&self, generation: u32) -> Result<PathBuf> {
+        let link = self.generation_link(generation);
+        let target = fs::read_link(&link).with_context(|| {
+            format!(
+                "Generation {} does not exist ({})",
+                generation,
+                link.display()
+            )
+        })?;
 
-        // Find the line with "(current)" marker
-        for line in stdout.lines() {
-            if line.contains("(current)")
-                && let Some(number_str) = line.split_whitespace().next()
-                && let Ok(number) = number_str.parse::<u32>()
-            {
-                return Ok(number);
+        // Profile links are normally absolute, but resolve relative ones against the profile dir
+        if target.is_absolute() {
+            Ok(target)
+        } else {
+            let parent = link.parent().unwrap_or(Path::new("/"));
+            Ok(parent.join(target))
+        }
+    }
+
+    fn add_gc_root(&self, name: &str, store_path: &Path) -> Result<()> {
+        fs::create_dir_all(&self.gc_root_dir).with_context(|| {
+            format!(
+                "Failed to create GC root directory: {}",
+                self.gc_root_dir.display()
+            )
+        })?;
+
+        let root = self.gc_root_dir.join(name);
+        match fs::read_link(&root) {
+            Ok(existing) if existing == store_path => return Ok(()),
+            Ok(_) => fs::remove_file(&root)
+                .with_context(|| format!("Failed to replace GC root: {}", root.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read GC root: {}", root.display()));
             }
         }
 
-        anyhow::bail!("Could not determine current generation")
+        symlink(store_path, &root)
+            .with_context(|| format!("Failed to create GC root: {}", root.display()))
     }
 
-    fn delete_generations(&self, generations: &[u32]) -> Result<()> {
-        if generations.is_empty() {
-            return Ok(());
+    fn remove_gc_root(&self, name: &str) -> Result<bool> {
+        let root = self.gc_root_dir.join(name);
+        match fs::remove_file(&root) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to remove GC root: {}", root.display()))
+            }
         }
+    }
 
-        // Build the generation list as separate arguments
-        let gen_list: Vec<String> = generations.iter().map(|g| g.to_string()).collect();
+    fn list_gc_roots(&self) -> Result<Vec<GcRoot>> {
+        let entries = match fs::read_dir(&self.gc_root_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read GC root directory: {}",
+                        self.gc_root_dir.display()
+                    )
+                });
+            }
+        };
 
-        // Execute: nix-env --delete-generations 1 2 3 -p /nix/var/nix/profiles/system
-        let output = Command::new("nix-env")
-            .arg("--delete-generations")
-            .args(&gen_list)
-            .arg("-p")
-            .arg(&self.profile_path)
-            .output()
-            .context("Failed to execute nix-env --delete-generations")?;
+        let mut roots = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            // Only symlinks are roots; ignore anything else an admin may have put here
+            if let Ok(store_path) = fs::read_link(entry.path()) {
+                roots.push(GcRoot {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    store_path,
+                });
+            }
+        }
+        roots.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(roots)
+    }
+
+    fn pin_profile(&self, name: &str, store_path: &Path) -> Result<()> {
+        let profiles_dir = self.system_profiles_dir();
+        fs::create_dir_all(&profiles_dir).with_context(|| {
+            format!(
+                "Failed to create profiles directory: {}",
+                profiles_dir.display()
+            )
+        })?;
+
+        // Execute: nix-env -p /nix/var/nix/profiles/system-profiles/keep-42 --set /nix/store/...
+        let profile = profiles_dir.join(name);
+        let output = self
+            .nix
+            .output(
+                self.nix
+                    .nix_env()
+                    .arg("-p")
+                    .arg(&profile)
+                    .arg("--set")
+                    .arg(store_path),
+            )
+            .context("Failed to execute nix-env --set")
+            .kind(ErrorKind::Runner)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            anyhow::bail!("nix-env --delete-generations failed: {}", stderr);
+            return Err(anyhow::anyhow!("nix-env --set failed: {}", stderr))
+                .kind(ErrorKind::Runner);
         }
 
         Ok(())
     }
+
+    fn unpin_profile(&self, name: &str) -> Result<bool> {
+        let profiles_dir = self.system_profiles_dir();
+        let entries = match fs::read_dir(&profiles_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to read profiles directory: {}",
+                        profiles_dir.display()
+                    )
+                });
+            }
+        };
+
+        // A profile is the `name` link plus one `name-N-link` per generation
+        let link_prefix = format!("{}-", name);
+        let mut removed = false;
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let is_generation_link = file_name
+                .strip_prefix(&link_prefix)
+                .and_then(|rest| rest.strip_suffix("-link"))
+                .is_some_and(|number| number.parse::<u32>().is_ok());
+
+            if file_name == name || is_generation_link {
+                fs::remove_file(entry.path()).with_context(|| {
+                    format!("Failed to remove profile link: {}", entry.path().display())
+                })?;
+                removed = true;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn store_path_exists(&self, store_path: &Path) -> Result<bool> {
+        match fs::symlink_metadata(store_path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to check store path: {}", store_path.display())),
+        }
+    }
+
+    fn restore_generation(&self, generation: u32, store_path: &Path) -> Result<()> {
+        // nix-env discovers generations by their links, so relinking brings the generation back
+        let link = self.generation_link(generation);
+        symlink(store_path, &link).with_context(|| {
+            format!(
+                "Failed to recreate generation {} link: {}",
+                generation,
+                link.display()
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn runner_in(tmp_dir: &TempDir) -> RealNixOsRunner {
+        let profile = tmp_dir.path().join("system");
+        RealNixOsRunner::with_profile(profile.to_string_lossy().into_owned())
+            .with_gc_root_dir(tmp_dir.path().join("gcroots"))
+            .with_gc_lock(tmp_dir.path().join("gc.lock"))
+            .with_booted_system(tmp_dir.path().join("booted-system"))
+    }
+
+    #[test]
+    fn test_runs_program() {
+        let name = "switch-to-configuration";
+        assert!(runs_program(
+            b"/nix/store/abc-nixos-system/bin/switch-to-configuration\0switch\0",
+            name
+        ));
+        assert!(runs_program(
+            b"/nix/store/def-perl/bin/perl\0/nix/store/abc/bin/switch-to-configuration\0boot\0",
+            name
+        ));
+        // Editors and greps on the script do not count
+        assert!(!runs_program(
+            b"vim\0/nix/store/abc/bin/switch-to-configuration\0",
+            name
+        ));
+        assert!(!runs_program(b"grep\0switch-to-configuration\0", name));
+        assert!(!runs_program(b"/bin/switch-to-configuration-old\0", name));
+        assert!(!runs_program(b"", name));
+    }
+
+    #[test]
+    fn test_is_system_profile() {
+        assert!(RealNixOsRunner::new().is_system_profile());
+        assert!(
+            RealNixOsRunner::with_profile(format!("{}/work", SYSTEM_PROFILES_DIR))
+                .is_system_profile()
+        );
+        assert!(
+            !RealNixOsRunner::with_profile("/home/alice/.nix-profile".to_string())
+                .is_system_profile()
+        );
+    }
+
+    #[test]
+    fn test_generation_store_path() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner_in(&tmp_dir);
+        symlink(
+            "/nix/store/abc-nixos-system-42",
+            tmp_dir.path().join("system-42-link"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.generation_store_path(42).unwrap(),
+            PathBuf::from("/nix/store/abc-nixos-system-42")
+        );
+        assert!(runner.generation_store_path(43).is_err());
+    }
+
+    #[test]
+    fn test_gc_roots_roundtrip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner_in(&tmp_dir);
+
+        assert!(runner.list_gc_roots().unwrap().is_empty());
+
+        runner
+            .add_gc_root("system-1", Path::new("/nix/store/aaa"))
+            .unwrap();
+        runner
+            .add_gc_root("system-2", Path::new("/nix/store/bbb"))
+            .unwrap();
+        // Re-pointing an existing root replaces it
+        runner
+            .add_gc_root("system-1", Path::new("/nix/store/ccc"))
+            .unwrap();
+
+        let roots = runner.list_gc_roots().unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].name, "system-1");
+        assert_eq!(roots[0].store_path, PathBuf::from("/nix/store/ccc"));
+
+        assert!(runner.remove_gc_root("system-1").unwrap());
+        assert!(!runner.remove_gc_root("system-1").unwrap());
+        assert_eq!(runner.list_gc_roots().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_restore_generation() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner_in(&tmp_dir);
+        let store_path = tmp_dir.path().join("store-path");
+        fs::create_dir(&store_path).unwrap();
+
+        assert!(runner.store_path_exists(&store_path).unwrap());
+        assert!(
+            !runner
+                .store_path_exists(&tmp_dir.path().join("gone"))
+                .unwrap()
+        );
+
+        runner.restore_generation(7, &store_path).unwrap();
+        assert_eq!(runner.generation_store_path(7).unwrap(), store_path);
+
+        // An existing generation is never overwritten
+        assert!(runner.restore_generation(7, &store_path).is_err());
+    }
+
+    #[test]
+    fn test_unpin_profile() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner_in(&tmp_dir);
+        let profiles_dir = tmp_dir.path().join("system-profiles");
+        fs::create_dir_all(&profiles_dir).unwrap();
+
+        symlink("keep-42-1-link", profiles_dir.join("keep-42")).unwrap();
+        symlink("/nix/store/aaa", profiles_dir.join("keep-42-1-link")).unwrap();
+        symlink("keep-420-1-link", profiles_dir.join("keep-420")).unwrap();
+        symlink("/nix/store/bbb", profiles_dir.join("keep-420-1-link")).unwrap();
+
+        assert!(runner.unpin_profile("keep-42").unwrap());
+        assert!(!runner.unpin_profile("keep-42").unwrap());
+
+        // Profiles sharing the prefix are left alone
+        assert!(fs::symlink_metadata(profiles_dir.join("keep-420")).is_ok());
+        assert!(fs::symlink_metadata(profiles_dir.join("keep-420-1-link")).is_ok());
+        assert!(fs::symlink_metadata(profiles_dir.join("keep-42")).is_err());
+        assert!(fs::symlink_metadata(profiles_dir.join("keep-42-1-link")).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_in_progress_detects_held_locks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner_in(&tmp_dir);
+        assert_eq!(runner.rebuild_in_progress().unwrap(), None);
+
+        let profile_lock = File::create(tmp_dir.path().join("system.lock")).unwrap();
+        profile_lock.lock().unwrap();
+        let reason = runner.rebuild_in_progress().unwrap().unwrap();
+        assert!(reason.contains("system.lock"));
+        profile_lock.unlock().unwrap();
+
+        let gc_lock = File::create(tmp_dir.path().join("gc.lock")).unwrap();
+        gc_lock.lock().unwrap();
+        let reason = runner.rebuild_in_progress().unwrap().unwrap();
+        assert!(reason.contains("garbage collector"));
+        drop(gc_lock);
+
+        assert_eq!(runner.rebuild_in_progress().unwrap(), None);
+    }
+
+    #[test]
+    fn test_booted_generation() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner_in(&tmp_dir);
+        symlink("/nix/store/aaa", tmp_dir.path().join("system-1-link")).unwrap();
+        symlink("/nix/store/bbb", tmp_dir.path().join("system-2-link")).unwrap();
+        assert_eq!(runner.booted_generation(), None);
+
+        symlink("/nix/store/aaa", tmp_dir.path().join("booted-system")).unwrap();
+        assert_eq!(runner.booted_generation(), Some(1));
+    }
 }
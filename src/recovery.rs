@@ -0,0 +1,182 @@
+use crate::error::{ErrorKind, ResultExt};
+use crate::fs_util::{self, FileAccess};
+use crate::paths;
+use crate::protected_state::StateLock;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// A generation recorded right before it was deleted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletedGeneration {
+    pub profile: String,
+    pub generation: u32,
+    pub store_path: PathBuf,
+    /// Seconds since the Unix epoch
+    pub deleted_at: u64,
+    /// While set, a GC root keeps the store path alive until this time (seconds since the Unix epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined_until: Option<u64>,
+}
+
+/// Record of deleted generations, used to restore them until the next GC
+pub struct RecoveryLog {
+    path: PathBuf,
+}
+
+impl RecoveryLog {
+    /// Create a recovery log stored at a specific path
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Open the recovery log at its default location
+    /// /var/lib/lock-generations/deleted.json when running as root,
+    /// otherwise ~/.local/state/lock-generations/deleted.json
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(paths::state_dir()?.join("deleted.json")))
+    }
+
+    /// Load all recorded deletions, oldest first
+    /// Returns an empty list if the file doesn't exist
+    pub fn entries(&self) -> Result<Vec<DeletedGeneration>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read recovery log: {}", self.path.display()))
+            .kind(ErrorKind::State)?;
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse recovery log: {}", self.path.display()))
+            .kind(ErrorKind::State)
+    }
+
+    /// Append deleted generations to the log
+    pub fn record(&self, deleted: &[DeletedGeneration]) -> Result<()> {
+        self.update(|entries| entries.extend_from_slice(deleted))
+    }
+
+    /// Find the most recent deletion of a generation in a profile
+    pub fn find(&self, profile: &str, generation: u32) -> Result<Option<DeletedGeneration>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .rev()
+            .find(|e| e.profile == profile && e.generation == generation))
+    }
+
+    /// Forget all recorded deletions of a generation in a profile
+    pub fn remove(&self, profile: &str, generation: u32) -> Result<()> {
+        self.update(|entries| {
+            entries.retain(|e| !(e.profile == profile && e.generation == generation))
+        })
+    }
+
+    /// Forget exactly these records, e.g. of generations that survived a failed deletion
+    pub fn forget(&self, records: &[DeletedGeneration]) -> Result<()> {
+        self.update(|entries| entries.retain(|e| !records.contains(e)))
+    }
+
+    /// Load, change and write back the entries under an exclusive lock,
+    /// so concurrent cleans do not lose each other's records
+    fn update(&self, change: impl FnOnce(&mut Vec<DeletedGeneration>)) -> Result<()> {
+        let _lock =
+            StateLock::exclusive(&self.path, FileAccess::default()).kind(ErrorKind::State)?;
+        let mut entries = self.entries()?;
+        change(&mut entries);
+        self.write(&entries)
+    }
+
+    /// Write the entries, atomically replacing the file
+    fn write(&self, entries: &[DeletedGeneration]) -> Result<()> {
+        let contents =
+            serde_json::to_string_pretty(entries).context("Failed to serialize recovery log")?;
+        fs_util::write_atomic(&self.path, contents.as_bytes())
+            .with_context(|| format!("Failed to save recovery log: {}", self.path.display()))
+            .kind(ErrorKind::State)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn deleted(profile: &str, generation: u32, deleted_at: u64) -> DeletedGeneration {
+        DeletedGeneration {
+            profile: profile.to_string(),
+            generation,
+            store_path: PathBuf::from(format!("/nix/store/{}-{}", deleted_at, generation)),
+            deleted_at,
+            quarantined_until: None,
+        }
+    }
+
+    #[test]
+    fn test_record_find_remove() {
+        let tmp_dir = TempDir::new().unwrap();
+        let log = RecoveryLog::new(tmp_dir.path().join("deleted.json"));
+        let system = "/nix/var/nix/profiles/system";
+
+        assert!(log.entries().unwrap().is_empty());
+        assert!(log.find(system, 1).unwrap().is_none());
+
+        log.record(&[deleted(system, 1, 100), deleted(system, 2, 100)])
+            .unwrap();
+        log.record(&[deleted(system, 1, 200), deleted("other", 1, 300)])
+            .unwrap();
+
+        // The most recent deletion in the right profile wins
+        assert_eq!(log.find(system, 1).unwrap().unwrap().deleted_at, 200);
+        assert_eq!(log.find(system, 2).unwrap().unwrap().deleted_at, 100);
+
+        log.remove(system, 1).unwrap();
+        assert!(log.find(system, 1).unwrap().is_none());
+        assert!(log.find("other", 1).unwrap().is_some());
+        assert_eq!(log.entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_forget() {
+        let tmp_dir = TempDir::new().unwrap();
+        let log = RecoveryLog::new(tmp_dir.path().join("deleted.json"));
+        let system = "/nix/var/nix/profiles/system";
+
+        log.record(&[deleted(system, 1, 100)]).unwrap();
+        log.record(&[deleted(system, 1, 200), deleted(system, 2, 200)])
+            .unwrap();
+
+        // Only the given records go, not earlier deletions of the same generation
+        log.forget(&[deleted(system, 1, 200)]).unwrap();
+        assert_eq!(
+            log.entries().unwrap(),
+            vec![deleted(system, 1, 100), deleted(system, 2, 200)]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_records_are_kept() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("deleted.json");
+
+        let threads: Vec<_> = (0..8)
+            .map(|generation| {
+                let log = RecoveryLog::new(path.clone());
+                std::thread::spawn(move || {
+                    for deleted_at in 0..10 {
+                        log.record(&[deleted("system", generation, deleted_at)])
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(RecoveryLog::new(path).entries().unwrap().len(), 80);
+    }
+}
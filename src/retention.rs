@@ -0,0 +1,534 @@
+use crate::command_runner::Generation;
+use crate::timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// An age, written as e.g. "12h", "14d" or "2w"
+///
+/// Keeps the unit it was written in, so "14d" is shown as "14d" rather than "2w". Ages
+/// compare by their length in seconds.
+#[derive(Debug, Clone, Copy, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Age {
+    secs: u64,
+    unit_secs: u64,
+}
+
+const HOUR: u64 = 3_600;
+const DAY: u64 = 86_400;
+const WEEK: u64 = 7 * DAY;
+
+impl Age {
+    /// An age of `n` hours
+    pub const fn hours(n: u64) -> Self {
+        Self {
+            secs: n.saturating_mul(HOUR),
+            unit_secs: HOUR,
+        }
+    }
+
+    /// An age of `n` days
+    pub const fn days(n: u64) -> Self {
+        Self {
+            secs: n.saturating_mul(DAY),
+            unit_secs: DAY,
+        }
+    }
+
+    /// An age of `n` weeks
+    pub const fn weeks(n: u64) -> Self {
+        Self {
+            secs: n.saturating_mul(WEEK),
+            unit_secs: WEEK,
+        }
+    }
+
+    /// Get the age in seconds
+    pub fn secs(&self) -> u64 {
+        self.secs
+    }
+}
+
+impl PartialEq for Age {
+    fn eq(&self, other: &Self) -> bool {
+        self.secs == other.secs
+    }
+}
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("invalid age '{}', expected e.g. 14d", s))?;
+        let unit_secs = match unit {
+            "h" => HOUR,
+            "d" => DAY,
+            "w" => WEEK,
+            _ => return Err(format!("invalid age '{}', use a unit of h, d or w", s)),
+        };
+        number
+            .checked_mul(unit_secs)
+            .map(|secs| Age { secs, unit_secs })
+            .ok_or_else(|| format!("invalid age '{}', too large", s))
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Age> for String {
+    fn from(age: Age) -> Self {
+        age.to_string()
+    }
+}
+
+impl fmt::Display for Age {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.unit_secs {
+            HOUR => "h",
+            DAY => "d",
+            _ => "w",
+        };
+        write!(f, "{}{}", self.secs / self.unit_secs, unit)
+    }
+}
+
+/// Rules deciding which unprotected generations `clean` keeps
+///
+/// A generation is kept if any rule keeps it. The time-based rules (`older_than` and the
+/// GFS rules `keep_daily`, `keep_weekly` and `keep_monthly`) keep generations whose
+/// creation time is unknown, rather than guessing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Name of the preset the rules come from, if any
+    #[serde(skip)]
+    pub name: Option<String>,
+    /// Keep the N most recent generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    /// Only delete generations older than this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<Age>,
+    /// Keep the newest generation of each of the last N days with generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_daily: Option<usize>,
+    /// Keep the newest generation of each of the last N weeks with generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest generation of each of the last N months with generations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_monthly: Option<usize>,
+    /// Rules that do not come from the preset `name`, e.g. ones set by flags on top of it
+    #[serde(skip)]
+    pub overridden: BTreeSet<&'static str>,
+}
+
+/// Why a generation is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepReason {
+    /// The currently active generation
+    Current,
+    /// The generation the running system was booted from
+    Booted,
+    /// Protected by a user, the shared state or the system layer
+    Protected,
+    /// Among the `keep_last` most recent generations
+    KeepLast(usize),
+    /// Not older than `older_than`
+    NewerThan(Age),
+    /// Newest generation of one of the last `keep_daily` days
+    Daily(usize),
+    /// Newest generation of one of the last `keep_weekly` weeks
+    Weekly(usize),
+    /// Newest generation of one of the last `keep_monthly` months
+    Monthly(usize),
+    /// A time-based rule applies, but the creation time is unknown
+    UnknownAge,
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepReason::Current => write!(f, "current generation"),
+            KeepReason::Booted => write!(f, "booted generation"),
+            KeepReason::Protected => write!(f, "protected"),
+            KeepReason::KeepLast(n) => write!(f, "keep_last = {}", n),
+            KeepReason::NewerThan(age) => write!(f, "older_than = {}", age),
+            KeepReason::Daily(n) => write!(f, "keep_daily = {}", n),
+            KeepReason::Weekly(n) => write!(f, "keep_weekly = {}", n),
+            KeepReason::Monthly(n) => write!(f, "keep_monthly = {}", n),
+            KeepReason::UnknownAge => write!(f, "creation time unknown"),
+        }
+    }
+}
+
+impl KeepReason {
+    /// Check if the reason is a rule of the retention policy, rather than a protection
+    pub fn is_rule(&self) -> bool {
+        !matches!(
+            self,
+            KeepReason::Current | KeepReason::Booted | KeepReason::Protected
+        )
+    }
+}
+
+/// The outcome of applying a retention policy to a profile's generations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Generations to keep, each with the first reason that keeps it
+    pub keep: BTreeMap<u32, KeepReason>,
+    /// Generations to delete, in ascending order
+    pub delete: Vec<u32>,
+}
+
+impl RetentionPolicy {
+    /// A policy keeping the N most recent generations
+    #[cfg(test)]
+    pub fn keep_last(n: usize) -> Self {
+        Self {
+            keep_last: Some(n),
+            ..Default::default()
+        }
+    }
+
+    /// Layer `other` on top of this policy; rules set in `other` win
+    /// Rules keep track of the preset they come from, see `preset_of`
+    pub fn merge(self, other: RetentionPolicy) -> Self {
+        let name = other.name.clone().or(self.name.clone());
+        let mut overridden = BTreeSet::new();
+        for ((rule, in_self), (_, in_other)) in self.rules().into_iter().zip(other.rules()) {
+            let origin = if in_other {
+                &other
+            } else if in_self {
+                &self
+            } else {
+                continue;
+            };
+            if origin.name != name || origin.overridden.contains(rule) {
+                overridden.insert(rule);
+            }
+        }
+
+        Self {
+            name,
+            keep_last: other.keep_last.or(self.keep_last),
+            older_than: other.older_than.or(self.older_than),
+            keep_daily: other.keep_daily.or(self.keep_daily),
+            keep_weekly: other.keep_weekly.or(self.keep_weekly),
+            keep_monthly: other.keep_monthly.or(self.keep_monthly),
+            overridden,
+        }
+    }
+
+    /// The rules by their name in config.toml, each with whether it is set
+    fn rules(&self) -> [(&'static str, bool); 5] {
+        [
+            ("keep_last", self.keep_last.is_some()),
+            ("older_than", self.older_than.is_some()),
+            ("keep_daily", self.keep_daily.is_some()),
+            ("keep_weekly", self.keep_weekly.is_some()),
+            ("keep_monthly", self.keep_monthly.is_some()),
+        ]
+    }
+
+    /// Name of the preset whose rule keeps a generation for `reason`
+    /// Returns None for protections, and for rules not supplied by the preset
+    pub fn preset_of(&self, reason: &KeepReason) -> Option<&str> {
+        let rules: &[&str] = match reason {
+            KeepReason::Current | KeepReason::Booted | KeepReason::Protected => return None,
+            KeepReason::KeepLast(_) => &["keep_last"],
+            KeepReason::NewerThan(_) => &["older_than"],
+            KeepReason::Daily(_) => &["keep_daily"],
+            KeepReason::Weekly(_) => &["keep_weekly"],
+            KeepReason::Monthly(_) => &["keep_monthly"],
+            KeepReason::UnknownAge => &["older_than", "keep_daily", "keep_weekly", "keep_monthly"],
+        };
+        if rules.iter().any(|rule| self.overridden.contains(rule)) {
+            return None;
+        }
+        self.name.as_deref()
+    }
+
+    /// Check if any rule depends on the creation time of generations
+    fn has_time_rules(&self) -> bool {
+        self.older_than.is_some()
+            || self.keep_daily.is_some()
+            || self.keep_weekly.is_some()
+            || self.keep_monthly.is_some()
+    }
+
+    /// Decide which generations to keep and which to delete
+    ///
+    /// # Arguments
+    ///
+    /// * `generations` - All generations of the profile
+    /// * `current` - The currently active generation
+    /// * `booted` - The generation the running system was booted from, if known
+    /// * `protected` - Generations protected by any layer
+    /// * `now` - The current time in seconds since the Unix epoch
+    pub fn plan(
+        &self,
+        generations: &[Generation],
+        current: u32,
+        booted: Option<u32>,
+        protected: &BTreeSet<u32>,
+        now: u64,
+    ) -> Plan {
+        let mut sorted: Vec<&Generation> = generations.iter().collect();
+        sorted.sort_unstable_by_key(|g| g.number);
+
+        // Rules are applied in order, the first one to keep a generation names the reason
+        let mut keep: BTreeMap<u32, KeepReason> = BTreeMap::new();
+        if sorted.iter().any(|g| g.number == current) {
+            keep.insert(current, KeepReason::Current);
+        }
+        if let Some(booted) = booted
+            && sorted.iter().any(|g| g.number == booted)
+        {
+            keep.entry(booted).or_insert(KeepReason::Booted);
+        }
+        for g in &sorted {
+            if protected.contains(&g.number) {
+                keep.entry(g.number).or_insert(KeepReason::Protected);
+            }
+        }
+
+        if let Some(n) = self.keep_last {
+            for g in sorted.iter().rev().take(n) {
+                keep.entry(g.number).or_insert(KeepReason::KeepLast(n));
+            }
+        }
+
+        if self.has_time_rules() {
+            for g in sorted.iter().filter(|g| g.created.is_none()) {
+                keep.entry(g.number).or_insert(KeepReason::UnknownAge);
+            }
+        }
+
+        if let Some(age) = self.older_than {
+            for g in &sorted {
+                if g.created
+                    .is_some_and(|created| now.saturating_sub(created) <= age.secs())
+                {
+                    keep.entry(g.number).or_insert(KeepReason::NewerThan(age));
+                }
+            }
+        }
+
+        if let Some(n) = self.keep_daily {
+            keep_newest_per_bucket(&sorted, &mut keep, n, day_bucket, KeepReason::Daily(n));
+        }
+        if let Some(n) = self.keep_weekly {
+            keep_newest_per_bucket(&sorted, &mut keep, n, week_bucket, KeepReason::Weekly(n));
+        }
+        if let Some(n) = self.keep_monthly {
+            keep_newest_per_bucket(&sorted, &mut keep, n, month_bucket, KeepReason::Monthly(n));
+        }
+
+        let delete = sorted
+            .iter()
+            .map(|g| g.number)
+            .filter(|number| !keep.contains_key(number))
+            .collect();
+
+        Plan { keep, delete }
+    }
+}
+
+/// Keep the newest generation of each of the last `n` buckets (days, weeks or months)
+/// `sorted` must be in ascending order; generations of unknown age are skipped
+fn keep_newest_per_bucket(
+    sorted: &[&Generation],
+    keep: &mut BTreeMap<u32, KeepReason>,
+    n: usize,
+    bucket_of: fn(u64) -> i64,
+    reason: KeepReason,
+) {
+    // Newest first, so the first generation seen in a bucket is its newest
+    let mut seen = HashSet::new();
+    for g in sorted.iter().rev() {
+        let Some(created) = g.created else {
+            continue;
+        };
+        let bucket = bucket_of(created);
+        if seen.contains(&bucket) {
+            continue;
+        }
+        if seen.len() == n {
+            break;
+        }
+        seen.insert(bucket);
+        keep.entry(g.number).or_insert(reason);
+    }
+}
+
+/// Day of a timestamp, in days since the Unix epoch (UTC)
+fn day_bucket(secs: u64) -> i64 {
+    (secs / 86_400) as i64
+}
+
+/// Week of a timestamp, with weeks starting on Monday (the epoch was a Thursday)
+fn week_bucket(secs: u64) -> i64 {
+    (day_bucket(secs) + 3).div_euclid(7)
+}
+
+/// Month of a timestamp, in months since year 0
+fn month_bucket(secs: u64) -> i64 {
+    let (year, month, _) = timestamp::civil_from_days(day_bucket(secs));
+    year * 12 + i64::from(month)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Monday 2024-01-15 00:00:00 UTC
+    const NOW: u64 = 1_705_276_800;
+
+    fn generations(created: &[(u32, Option<u64>)]) -> Vec<Generation> {
+        created
+            .iter()
+            .map(|&(number, created)| Generation { number, created })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_age() {
+        assert_eq!("14d".parse::<Age>().unwrap(), Age::days(14));
+        assert_eq!("2w".parse::<Age>().unwrap(), Age::days(14));
+        assert_eq!("12h".parse::<Age>().unwrap(), Age::hours(12));
+        assert!("14".parse::<Age>().is_err());
+        assert!("d".parse::<Age>().is_err());
+        assert!("14m".parse::<Age>().is_err());
+        assert!("99999999999999999w".parse::<Age>().is_err());
+
+        // Shown the way it was written
+        assert_eq!(Age::days(14).to_string(), "14d");
+        assert_eq!("2w".parse::<Age>().unwrap().to_string(), "2w");
+        assert_eq!(Age::hours(12).to_string(), "12h");
+    }
+
+    #[test]
+    fn test_keep_last_and_protected() {
+        let gens = generations(&[(1, None), (2, None), (3, None), (4, None), (5, None)]);
+        let protected = BTreeSet::from([1]);
+
+        let plan = RetentionPolicy::keep_last(2).plan(&gens, 3, None, &protected, NOW);
+        assert_eq!(plan.delete, vec![2]);
+        assert_eq!(plan.keep[&1], KeepReason::Protected);
+        assert_eq!(plan.keep[&3], KeepReason::Current);
+        assert_eq!(plan.keep[&5], KeepReason::KeepLast(2));
+    }
+
+    #[test]
+    fn test_older_than() {
+        let gens = generations(&[
+            (1, Some(NOW - 30 * DAY)),
+            (2, Some(NOW - 20 * DAY)),
+            (3, Some(NOW - 10 * DAY)),
+            (4, None),
+            (5, Some(NOW)),
+        ]);
+        let policy = RetentionPolicy {
+            older_than: Some(Age::days(14)),
+            ..Default::default()
+        };
+
+        let plan = policy.plan(&gens, 5, None, &BTreeSet::new(), NOW);
+        assert_eq!(plan.delete, vec![1, 2]);
+        assert_eq!(plan.keep[&3], KeepReason::NewerThan(Age::days(14)));
+        // Unknown creation times are never deleted by a time-based rule
+        assert_eq!(plan.keep[&4], KeepReason::UnknownAge);
+    }
+
+    #[test]
+    fn test_gfs() {
+        let gens = generations(&[
+            // Two generations in December, two on the same day in January
+            (1, Some(NOW - 40 * DAY)),
+            (2, Some(NOW - 35 * DAY)),
+            (3, Some(NOW - 6 * DAY)),
+            (4, Some(NOW - 6 * DAY + 60)),
+            (5, Some(NOW - 2 * DAY)),
+            (6, Some(NOW)),
+        ]);
+        let policy = RetentionPolicy {
+            keep_daily: Some(2),
+            keep_monthly: Some(2),
+            ..Default::default()
+        };
+
+        let plan = policy.plan(&gens, 6, None, &BTreeSet::new(), NOW);
+        assert_eq!(plan.keep[&5], KeepReason::Daily(2));
+        // The newest generation of December is kept, January's is already kept as current
+        assert_eq!(plan.keep[&2], KeepReason::Monthly(2));
+        assert_eq!(plan.delete, vec![1, 3, 4]);
+
+        let weekly = RetentionPolicy {
+            keep_weekly: Some(3),
+            ..Default::default()
+        };
+        let plan = weekly.plan(&gens, 6, None, &BTreeSet::new(), NOW);
+        // NOW is a Monday, so 4 and 5 fall into the previous week
+        assert_eq!(plan.keep[&5], KeepReason::Weekly(3));
+        assert!(plan.delete.contains(&4));
+        assert_eq!(plan.keep[&2], KeepReason::Weekly(3));
+    }
+
+    #[test]
+    fn test_merge() {
+        let base = RetentionPolicy {
+            keep_last: Some(5),
+            keep_monthly: Some(12),
+            ..Default::default()
+        };
+        let merged = base.merge(RetentionPolicy {
+            name: Some("laptop".to_string()),
+            keep_last: Some(3),
+            ..Default::default()
+        });
+
+        assert_eq!(merged.name.as_deref(), Some("laptop"));
+        assert_eq!(merged.keep_last, Some(3));
+        assert_eq!(merged.keep_monthly, Some(12));
+        // Only the rules the preset supplied carry its name
+        assert_eq!(merged.preset_of(&KeepReason::KeepLast(3)), Some("laptop"));
+        assert_eq!(merged.preset_of(&KeepReason::Monthly(12)), None);
+    }
+
+    #[test]
+    fn test_flags_override_preset_rules() {
+        let preset = RetentionPolicy {
+            name: Some("server".to_string()),
+            keep_last: Some(10),
+            older_than: Some(Age::days(14)),
+            ..Default::default()
+        };
+        let merged = preset.merge(RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        });
+
+        assert_eq!(merged.name.as_deref(), Some("server"));
+        assert_eq!(merged.preset_of(&KeepReason::KeepLast(2)), None);
+        assert_eq!(
+            merged.preset_of(&KeepReason::NewerThan(Age::days(14))),
+            Some("server")
+        );
+        assert_eq!(merged.preset_of(&KeepReason::UnknownAge), Some("server"));
+        assert_eq!(merged.preset_of(&KeepReason::Current), None);
+    }
+}
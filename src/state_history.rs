@@ -0,0 +1,158 @@
+use crate::fs_util::{self, FileAccess};
+use crate::paths;
+use crate::timestamp;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Number of snapshots kept unless configured otherwise
+pub const DEFAULT_HISTORY_SIZE: usize = 10;
+
+/// The contents of a state file right before a save replaced them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// When the save replacing this state ran, in seconds since the Unix epoch
+    pub saved_at: u64,
+    /// The real user who ran the save
+    pub user: String,
+    /// The state file as it was, None if it did not exist yet
+    pub state: Option<serde_json::Value>,
+}
+
+/// Bounded history of snapshots of a state file, oldest first
+///
+/// Stored next to the state file, e.g. protected.json.history
+#[derive(Debug, Clone)]
+pub struct StateHistory {
+    path: PathBuf,
+    size: usize,
+}
+
+impl StateHistory {
+    /// The history of the state file at `state_path`, keeping up to `size` snapshots
+    pub fn for_state_file(state_path: &Path, size: usize) -> Self {
+        let mut name = state_path.file_name().unwrap_or_default().to_os_string();
+        name.push(".history");
+        Self {
+            path: state_path.with_file_name(name),
+            size,
+        }
+    }
+
+    /// Load all snapshots, oldest first
+    /// Returns an empty list if the history doesn't exist
+    pub fn entries(&self) -> Result<Vec<Snapshot>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read state history: {}", self.path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse state history: {}", self.path.display()))
+    }
+
+    /// Take a snapshot of the current contents of the state file before it is replaced
+    /// Returns None if the history is disabled
+    pub fn snapshot(&self, state_path: &Path) -> Result<Option<Snapshot>> {
+        if self.size == 0 {
+            return Ok(None);
+        }
+
+        let state = if state_path.exists() {
+            let contents = fs::read_to_string(state_path)
+                .with_context(|| format!("Failed to read config file: {}", state_path.display()))?;
+            Some(serde_json::from_str(&contents).with_context(|| {
+                format!("Failed to parse config file: {}", state_path.display())
+            })?)
+        } else {
+            None
+        };
+
+        Ok(Some(Snapshot {
+            saved_at: timestamp::now(),
+            user: paths::invoking_user_name(),
+            state,
+        }))
+    }
+
+    /// Record a snapshot once the save replacing it went through
+    /// The oldest snapshots are dropped beyond the history size
+    pub fn push(&self, snapshot: Snapshot, access: FileAccess) -> Result<()> {
+        let mut entries = self.entries()?;
+        entries.push(snapshot);
+        let excess = entries.len().saturating_sub(self.size);
+        entries.drain(..excess);
+        self.write(&entries, access)
+    }
+
+    /// Remove and return the most recent snapshot
+    pub fn pop(&self, access: FileAccess) -> Result<Option<Snapshot>> {
+        let mut entries = self.entries()?;
+        let last = entries.pop();
+        if last.is_some() {
+            self.write(&entries, access)?;
+        }
+        Ok(last)
+    }
+
+    /// Write the snapshots, atomically replacing the history
+    fn write(&self, entries: &[Snapshot], access: FileAccess) -> Result<()> {
+        let contents =
+            serde_json::to_string_pretty(entries).context("Failed to serialize state history")?;
+        fs_util::write_atomic_as(&self.path, contents.as_bytes(), access)
+            .with_context(|| format!("Failed to save state history: {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_push_and_pop() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_path = tmp_dir.path().join("protected.json");
+        let history = StateHistory::for_state_file(&state_path, 2);
+        let access = FileAccess::default();
+
+        // Before the first save there is no state file
+        let snapshot = history.snapshot(&state_path).unwrap().unwrap();
+        assert!(snapshot.state.is_none());
+        history.push(snapshot, access).unwrap();
+        for generation in [1, 2] {
+            fs::write(
+                &state_path,
+                format!("{{\"protected_generations\": [{}]}}", generation),
+            )
+            .unwrap();
+            let snapshot = history.snapshot(&state_path).unwrap().unwrap();
+            history.push(snapshot, access).unwrap();
+        }
+
+        // Only the two most recent snapshots are kept
+        let entries = history.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].state.as_ref().unwrap()["protected_generations"][0],
+            1
+        );
+
+        let last = history.pop(access).unwrap().unwrap();
+        assert_eq!(last.state.unwrap()["protected_generations"][0], 2);
+        assert_eq!(history.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_size_zero_disables_history() {
+        let tmp_dir = TempDir::new().unwrap();
+        let state_path = tmp_dir.path().join("protected.json");
+        let history = StateHistory::for_state_file(&state_path, 0);
+
+        assert!(history.snapshot(&state_path).unwrap().is_none());
+        assert!(history.entries().unwrap().is_empty());
+        assert!(history.pop(FileAccess::default()).unwrap().is_none());
+    }
+}